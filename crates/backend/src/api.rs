@@ -0,0 +1,430 @@
+//! HTTP/JSON endpoints mirroring [`ClientPackage`](pizza_bot_rs_common::communication::ClientPackage)
+//!
+//! Edit and organizer tokens are passed as `Authorization: Bearer <token>`,
+//! responses carry the same enums as the WebSocket protocol.
+//! Mutating endpoints accept an `Idempotency-Key` header, see [`Request`](pizza_bot_rs_common::communication::Request).
+//! Single orders can also be sent as `text/plain` in the [`syntax`] of the CLI and the chat bot.
+
+use axum::{
+    async_trait, extract::{FromRequest, FromRequestParts, Path, Query, State}, http::{header, request::Parts, StatusCode}, response::{IntoResponse, Response}, routing::{get, post, put}, Json, Router
+};
+use axum_extra::TypedHeader;
+use headers::{authorization::Bearer, Authorization};
+use serde::Deserialize;
+use pizza_bot_rs_common::{communication::{self, ClientPackage, DeleteOrderResponse, EditOrderResponse, GetOrderResponse, IdempotencyKey, MakeOrderResponse, QueryEventsResponse, RevertOrderResponse, RollbackResponse, SetAnnouncementResponse}, export::ExportFormat, orders::{Order, OrderRequest, OrderStateVersion}, syntax};
+
+use tracing::info;
+
+use crate::{audit::Origin, error::ProtocolError, idempotency::{self, RequestKey}, state::HandlerState};
+
+type Token = Option<TypedHeader<Authorization<Bearer>>>;
+
+pub(crate) fn router() -> Router<HandlerState> {
+    Router::new()
+        .route("/orders", get(list_orders).post(make_order))
+        .route("/orders/:name", get(get_order).post(make_named_order).put(edit_order).delete(delete_order))
+        .route("/orders/:name/revert", post(revert_order))
+        .route("/rollback", post(rollback))
+        .route("/announcement", put(set_announcement))
+        .route("/presence", get(presence))
+        .route("/events", get(query_events))
+        .route("/export", get(export))
+        // Keep unknown endpoints from falling through to the frontend
+        .fallback(|| async { StatusCode::NOT_FOUND })
+}
+
+fn token(header: &Token) -> Option<&str> {
+    header.as_ref().map(|TypedHeader(auth)| auth.token())
+}
+
+/// The optional `Idempotency-Key` header
+struct Idempotency(Option<IdempotencyKey>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Idempotency {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get("idempotency-key") else {
+            return Ok(Self(None))
+        };
+
+        match value.to_str() {
+            Ok(key) if idempotency::is_valid_key(key) => Ok(Self(Some(key.to_owned()))),
+            _ => Err((StatusCode::BAD_REQUEST, Json(ProtocolError::InvalidIdempotencyKey.to_server_error())).into_response()),
+        }
+    }
+}
+
+/// An [`Order`] as JSON, or in the [`syntax`] of the CLI and the chat bot with `Content-Type: text/plain`
+struct OrderBody(Order);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for OrderBody {
+    type Rejection = Response;
+
+    async fn from_request(request: axum::extract::Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_text = request.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("text/plain"));
+
+        if !is_text {
+            let Json(order) = Json::<Order>::from_request(request, state).await.map_err(IntoResponse::into_response)?;
+            return Ok(Self(order))
+        }
+
+        let text = String::from_request(request, state).await.map_err(IntoResponse::into_response)?;
+        match syntax::parse_order(&text) {
+            Ok(order) => Ok(Self(order)),
+            Err(err) => Err((StatusCode::UNPROCESSABLE_ENTITY, err.to_string()).into_response()),
+        }
+    }
+}
+
+/// Scopes the key to the client and the equivalent WebSocket package, see [`RequestKey::new`]
+fn request_key(origin: &Origin, key: Option<IdempotencyKey>, package: impl FnOnce() -> ClientPackage) -> Option<RequestKey> {
+    key.map(|key| RequestKey::new(origin.address.ip(), key, &package()))
+}
+
+/// Counts a mutation against the message limit of its address, since each one rebalances all orders.
+/// Returns the rejection if the limit is exceeded.
+fn message_limited(state: &HandlerState, origin: &Origin) -> Option<Response> {
+    if state.message_limits.try_take(origin.address.ip()) {
+        return None
+    }
+
+    let err = ProtocolError::MessageRateLimited;
+    info!("{} was rate limited: {err}", origin.address);
+    Some((StatusCode::TOO_MANY_REQUESTS, Json(err.to_server_error())).into_response())
+}
+
+/// The key of the request was used before for a different one
+fn idempotency_key_reused() -> Response {
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(ProtocolError::IdempotencyKeyReused.to_server_error())).into_response()
+}
+
+async fn list_orders(State(state): State<HandlerState>) -> Response {
+    let orders = state.orders.lock().await;
+    Json(orders.to_full_data()).into_response()
+}
+
+/// Names of the orders with an open WebSocket connection
+async fn presence(State(state): State<HandlerState>) -> Response {
+    Json(state.presence.names()).into_response()
+}
+
+async fn get_order(State(state): State<HandlerState>, Path(name): Path<String>) -> Response {
+    match state.get_order(&name).await {
+        GetOrderResponse::Success(full) => Json(full).into_response(),
+        response @ GetOrderResponse::NameNotFound => (StatusCode::NOT_FOUND, Json(response)).into_response(),
+    }
+}
+
+async fn make_order(State(state): State<HandlerState>, origin: Origin, Idempotency(key): Idempotency, Json(request): Json<OrderRequest>) -> Response {
+    make(state, origin, key, request).await
+}
+
+/// Like [`make_order`], with the name in the path instead of the body
+async fn make_named_order(State(state): State<HandlerState>, Path(name): Path<String>, origin: Origin, Idempotency(key): Idempotency, OrderBody(order): OrderBody) -> Response {
+    make(state, origin, key, OrderRequest { name, order }).await
+}
+
+async fn make(state: HandlerState, origin: Origin, key: Option<IdempotencyKey>, request: OrderRequest) -> Response {
+    if let Some(rejection) = message_limited(&state, &origin) {
+        return rejection
+    }
+
+    let key = request_key(&origin, key, || ClientPackage::MakeOrder(request.clone()));
+    let response = match state.make_order_limited(request, key, &origin).await {
+        Ok(communication::Response::MakeOrder(response)) => response,
+        Err(err @ ProtocolError::OrderRateLimited) => return (StatusCode::TOO_MANY_REQUESTS, Json(err.to_server_error())).into_response(),
+        _ => return idempotency_key_reused(),
+    };
+    let status = match response {
+        MakeOrderResponse::Success(_) => StatusCode::CREATED,
+        MakeOrderResponse::NameAlreadyRegistered |
+        MakeOrderResponse::TooManyOrders => StatusCode::CONFLICT,
+        MakeOrderResponse::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+    };
+
+    (status, Json(response)).into_response()
+}
+
+/// `?expected_version=`, see [`ClientPackage::EditOrder`](pizza_bot_rs_common::communication::ClientPackage::EditOrder)
+#[derive(Deserialize)]
+struct EditPrecondition {
+    expected_version: Option<OrderStateVersion>,
+}
+
+async fn edit_order(State(state): State<HandlerState>, Path(name): Path<String>, Query(precondition): Query<EditPrecondition>, origin: Origin, Idempotency(key): Idempotency, header: Token, OrderBody(order): OrderBody) -> Response {
+    if let Some(rejection) = message_limited(&state, &origin) {
+        return rejection
+    }
+    let request = OrderRequest { name, order };
+    let key = request_key(&origin, key, || ClientPackage::EditOrder {
+        request: request.clone(),
+        token: token(&header).unwrap_or_default().to_owned(),
+        expected_version: precondition.expected_version,
+    });
+    let response = state.idempotency.run(key, async {
+        communication::Response::EditOrder(state.edit_order(request, token(&header), precondition.expected_version, &origin).await)
+    }).await;
+    let Ok(communication::Response::EditOrder(response)) = response else {
+        return idempotency_key_reused()
+    };
+    let status = match response {
+        EditOrderResponse::Success => StatusCode::OK,
+        EditOrderResponse::NameNotFound => StatusCode::NOT_FOUND,
+        EditOrderResponse::InvalidToken => StatusCode::FORBIDDEN,
+        EditOrderResponse::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        EditOrderResponse::Conflict(_) => StatusCode::CONFLICT,
+    };
+
+    (status, Json(response)).into_response()
+}
+
+async fn delete_order(State(state): State<HandlerState>, Path(name): Path<String>, origin: Origin, Idempotency(key): Idempotency, header: Token) -> Response {
+    if let Some(rejection) = message_limited(&state, &origin) {
+        return rejection
+    }
+    let key = request_key(&origin, key, || ClientPackage::DeleteOrder { name: name.clone(), token: token(&header).unwrap_or_default().to_owned() });
+    let response = state.idempotency.run(key, async {
+        communication::Response::DeleteOrder(state.delete_order(&name, token(&header), &origin).await)
+    }).await;
+    let Ok(communication::Response::DeleteOrder(response)) = response else {
+        return idempotency_key_reused()
+    };
+    let status = match response {
+        DeleteOrderResponse::Success => StatusCode::OK,
+        DeleteOrderResponse::NameNotFound => StatusCode::NOT_FOUND,
+        DeleteOrderResponse::InvalidToken => StatusCode::FORBIDDEN,
+    };
+
+    (status, Json(response)).into_response()
+}
+
+async fn revert_order(State(state): State<HandlerState>, Path(name): Path<String>, origin: Origin, Idempotency(key): Idempotency, header: Token) -> Response {
+    if let Some(rejection) = message_limited(&state, &origin) {
+        return rejection
+    }
+    let key = request_key(&origin, key, || ClientPackage::RevertOrder { name: name.clone(), token: token(&header).unwrap_or_default().to_owned() });
+    let response = state.idempotency.run(key, async {
+        communication::Response::RevertOrder(state.revert_order(&name, token(&header), &origin).await)
+    }).await;
+    let Ok(communication::Response::RevertOrder(response)) = response else {
+        return idempotency_key_reused()
+    };
+    let status = match response {
+        RevertOrderResponse::Success(_) => StatusCode::OK,
+        RevertOrderResponse::NameNotFound => StatusCode::NOT_FOUND,
+        RevertOrderResponse::InvalidToken => StatusCode::FORBIDDEN,
+        RevertOrderResponse::NothingToRevert => StatusCode::CONFLICT,
+    };
+
+    (status, Json(response)).into_response()
+}
+
+/// Requires the organizer token
+async fn rollback(State(state): State<HandlerState>, origin: Origin, Idempotency(key): Idempotency, header: Token, Json(version): Json<OrderStateVersion>) -> Response {
+    if let Some(rejection) = message_limited(&state, &origin) {
+        return rejection
+    }
+    let key = request_key(&origin, key, || ClientPackage::Rollback { version, token: token(&header).unwrap_or_default().to_owned() });
+    let response = state.idempotency.run(key, async {
+        communication::Response::Rollback(state.rollback(version, token(&header), &origin).await)
+    }).await;
+    let Ok(communication::Response::Rollback(response)) = response else {
+        return idempotency_key_reused()
+    };
+    let status = match response {
+        RollbackResponse::Success => StatusCode::OK,
+        RollbackResponse::InvalidToken => StatusCode::FORBIDDEN,
+        RollbackResponse::VersionNotFound => StatusCode::NOT_FOUND,
+        RollbackResponse::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (status, Json(response)).into_response()
+}
+
+#[derive(Deserialize)]
+struct EventFilter {
+    name: Option<String>,
+}
+
+/// Requires the organizer token
+async fn query_events(State(state): State<HandlerState>, Query(filter): Query<EventFilter>, header: Token) -> Response {
+    let response = state.query_events(filter.name.as_deref(), token(&header)).await;
+    let status = match response {
+        QueryEventsResponse::Success(_) => StatusCode::OK,
+        QueryEventsResponse::InvalidToken => StatusCode::FORBIDDEN,
+        QueryEventsResponse::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (status, Json(response)).into_response()
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: Option<ExportFormat>,
+}
+
+/// `?format=text|csv|markdown`, plain text by default
+async fn export(State(state): State<HandlerState>, Query(query): Query<ExportQuery>) -> Response {
+    let format = query.format.unwrap_or(ExportFormat::Text);
+    let export = state.export(format).await;
+
+    ([(header::CONTENT_TYPE, format.content_type())], export).into_response()
+}
+
+async fn set_announcement(State(state): State<HandlerState>, origin: Origin, Idempotency(key): Idempotency, header: Token, Json(announcement): Json<String>) -> Response {
+    if let Some(rejection) = message_limited(&state, &origin) {
+        return rejection
+    }
+    let key = request_key(&origin, key, || ClientPackage::SetAnnouncement { announcement: announcement.clone(), token: token(&header).unwrap_or_default().to_owned() });
+    let response = state.idempotency.run(key, async {
+        communication::Response::SetAnnouncement(state.set_announcement(announcement, token(&header), &origin).await)
+    }).await;
+    let Ok(communication::Response::SetAnnouncement(response)) = response else {
+        return idempotency_key_reused()
+    };
+    let status = match response {
+        SetAnnouncementResponse::Success => StatusCode::OK,
+        SetAnnouncementResponse::InvalidToken => StatusCode::FORBIDDEN,
+    };
+
+    (status, Json(response)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use axum::{body::Body, extract::ConnectInfo, http::{Method, Request}};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::{config::Config, state::AppState};
+
+    use super::*;
+
+    /// Sends a request from a fixed client address, returning the status and the JSON body
+    async fn send(state: &HandlerState, method: Method, uri: &str, token: Option<&str>, key: Option<&str>, body: Option<Value>) -> (StatusCode, Value) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        if let Some(key) = key {
+            request = request.header("idempotency-key", key);
+        }
+        let body = match body {
+            Some(body) => {
+                request = request.header(header::CONTENT_TYPE, "application/json");
+                Body::from(body.to_string())
+            },
+            None => Body::empty(),
+        };
+        let mut request = request.body(body).unwrap();
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 50000))));
+
+        let response = router().with_state(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    fn order(amounts: [usize; 3]) -> Value {
+        json!({ "amounts": amounts, "preference": 0.5 })
+    }
+
+    /// Makes the order of `name`, returning its token
+    async fn make(state: &HandlerState, name: &str) -> String {
+        let (status, body) = send(state, Method::POST, "/orders", None, None, Some(json!({ "name": name, "order": order([2, 0, 0]) }))).await;
+        assert_eq!(status, StatusCode::CREATED);
+        body["Success"].as_str().unwrap().to_owned()
+    }
+
+    fn state_with(test: &str, configure: impl FnOnce(&mut Config)) -> HandlerState {
+        Arc::new(AppState::temporary_with(test, configure))
+    }
+
+    #[tokio::test]
+    async fn make_maps_the_responses_to_status_codes() {
+        let state = state_with("api-make", |config| config.limits.max_orders = 2);
+
+        make(&state, "alice").await;
+        let (status, body) = send(&state, Method::POST, "/orders", None, None, Some(json!({ "name": "alice", "order": order([2, 0, 0]) }))).await;
+        assert_eq!((status, body), (StatusCode::CONFLICT, json!("NameAlreadyRegistered")));
+
+        let (status, body) = send(&state, Method::POST, "/orders/bob", None, None, Some(order([0, 0, 0]))).await;
+        assert_eq!((status, body), (StatusCode::UNPROCESSABLE_ENTITY, json!({ "Invalid": "NoSlices" })));
+
+        make(&state, "bob").await;
+        let (status, body) = send(&state, Method::POST, "/orders/carol", None, None, Some(order([2, 0, 0]))).await;
+        assert_eq!((status, body), (StatusCode::CONFLICT, json!("TooManyOrders")));
+    }
+
+    #[tokio::test]
+    async fn make_rejects_rate_limited_orders_and_reused_keys() {
+        let state = state_with("api-make-limits", |config| config.limits.orders_per_ip_per_hour = 1);
+
+        let (status, _) = send(&state, Method::POST, "/orders/alice", None, Some("key"), Some(order([2, 0, 0]))).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = send(&state, Method::POST, "/orders/bob", None, Some("key"), Some(order([2, 0, 0]))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], json!("MalformedRequest"));
+
+        let (status, body) = send(&state, Method::POST, "/orders/bob", None, None, Some(order([2, 0, 0]))).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], json!("RateLimited"));
+    }
+
+    #[tokio::test]
+    async fn rejects_mutations_over_the_message_limit() {
+        let state = state_with("api-message-limit", |config| config.limits.message_burst = 1);
+
+        make(&state, "alice").await;
+        let (status, body) = send(&state, Method::DELETE, "/orders/alice", None, None, None).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], json!("RateLimited"));
+    }
+
+    #[tokio::test]
+    async fn edit_maps_the_responses_to_status_codes() {
+        let state = state_with("api-edit", |_| {});
+        let token = make(&state, "alice").await;
+
+        let (status, body) = send(&state, Method::PUT, "/orders/alice", Some(&token), None, Some(order([3, 0, 0]))).await;
+        assert_eq!((status, body), (StatusCode::OK, json!("Success")));
+
+        let (status, body) = send(&state, Method::PUT, "/orders/bob", Some(&token), None, Some(order([3, 0, 0]))).await;
+        assert_eq!((status, body), (StatusCode::NOT_FOUND, json!("NameNotFound")));
+
+        let (status, body) = send(&state, Method::PUT, "/orders/alice", Some("wrong"), None, Some(order([3, 0, 0]))).await;
+        assert_eq!((status, body), (StatusCode::FORBIDDEN, json!("InvalidToken")));
+
+        let (status, body) = send(&state, Method::PUT, "/orders/alice", Some(&token), None, Some(order([0, 0, 0]))).await;
+        assert_eq!((status, body), (StatusCode::UNPROCESSABLE_ENTITY, json!({ "Invalid": "NoSlices" })));
+
+        // The order is at version 2 after the first edit
+        let (status, body) = send(&state, Method::PUT, "/orders/alice?expected_version=1", Some(&token), None, Some(order([4, 0, 0]))).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["Conflict"]["order"]["amounts"], json!([3, 0, 0]));
+    }
+
+    #[tokio::test]
+    async fn delete_maps_the_responses_to_status_codes() {
+        let state = state_with("api-delete", |_| {});
+        let token = make(&state, "alice").await;
+
+        let (status, body) = send(&state, Method::DELETE, "/orders/alice", Some("wrong"), None, None).await;
+        assert_eq!((status, body), (StatusCode::FORBIDDEN, json!("InvalidToken")));
+
+        let (status, body) = send(&state, Method::DELETE, "/orders/alice", Some(&token), None, None).await;
+        assert_eq!((status, body), (StatusCode::OK, json!("Success")));
+
+        let (status, body) = send(&state, Method::DELETE, "/orders/alice", Some(&token), None, None).await;
+        assert_eq!((status, body), (StatusCode::NOT_FOUND, json!("NameNotFound")));
+    }
+}
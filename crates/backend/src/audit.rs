@@ -0,0 +1,370 @@
+//! Append-only log of every accepted mutation, kept next to the persisted state
+//!
+//! Replaying the log through [`OrderStateExt`] rebuilds the orders at any past version.
+//! Events are appended by a dedicated thread, so recording one never blocks a request on the disk.
+
+use std::{fs::File, io::{self, BufRead, Write}, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, sync::mpsc, time::{SystemTime, UNIX_EPOCH}};
+
+use axum::{
+    async_trait, extract::{rejection::ExtensionRejection, ConnectInfo, FromRequestParts}, http::{header, request::Parts, HeaderMap}
+};
+use pizza_bot_rs_common::{audit::{AuditChange, AuditEvent}, orders::{OrderState, OrderStateVersion}};
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+use crate::{config::Config, state::{HandlerState, OrderStateExt}};
+
+const EVENTS_FILE: &str = "events.jsonl";
+
+/// Where a request came from
+pub(crate) struct Origin {
+    /// Address of the client, see [`client_address`]
+    pub address: SocketAddr,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<HandlerState> for Origin {
+    type Rejection = ExtensionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &HandlerState) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state).await?;
+        let address = client_address(peer, &parts.headers, &state.config.trusted_proxies);
+        let user_agent = parts.headers
+            .get(header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(str::to_owned);
+
+        Ok(Self { address, user_agent })
+    }
+}
+
+/// The connecting `peer`, or if it is a trusted proxy, the address it forwarded the request for.
+/// Proxies append to `X-Forwarded-For`, so the last entry not added by a trusted proxy is the client.
+/// The port of the client is not forwarded and left at 0.
+fn client_address(peer: SocketAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> SocketAddr {
+    let forwarded: Vec<_> = headers.get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    let mut address = peer;
+    for entry in forwarded.into_iter().rev() {
+        if !trusted_proxies.contains(&address.ip()) {
+            break
+        }
+        // Anything else was not written by a proxy, so the last trusted one is the best guess
+        let Ok(ip) = entry.parse() else {
+            break
+        };
+        address = SocketAddr::new(ip, 0);
+    }
+
+    address
+}
+
+enum Command {
+    Append(AuditEvent),
+    /// Answered once every event sent before is written
+    Flush(oneshot::Sender<()>),
+}
+
+pub(crate) struct AuditLog {
+    data_dir: PathBuf,
+    writer: mpsc::Sender<Command>,
+}
+
+impl AuditLog {
+    /// Opens the log and starts the thread appending to it
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        let path = data_dir.join(EVENTS_FILE);
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+
+        // A crash can cut off the last event, which would corrupt the first one appended after it
+        let content = std::fs::read(&path)?;
+        let complete = complete_length(&content);
+        if complete < content.len() {
+            warn!("dropping the incomplete last event of the event log");
+            file.set_len(complete as u64)?;
+        }
+
+        let (writer, commands) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(String::from("event log"))
+            .spawn(move || write_events(file, commands))?;
+
+        Ok(Self {
+            data_dir: data_dir.to_owned(),
+            writer,
+        })
+    }
+
+    /// Queues an event for appending, failures are only logged so they never reject a request
+    pub fn record(&self, origin: &Origin, version: OrderStateVersion, by_organizer: bool, change: AuditChange) {
+        let event = AuditEvent {
+            version,
+            timestamp_ms: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64),
+            address: origin.address,
+            user_agent: origin.user_agent.clone(),
+            by_organizer,
+            change,
+        };
+
+        if self.writer.send(Command::Append(event)).is_err() {
+            error!("could not append to the event log, the writer stopped");
+        }
+    }
+
+    /// Waits until every event recorded so far is written
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.writer.send(Command::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// Every event recorded so far, see [`load`]
+    pub async fn events(&self) -> io::Result<Vec<AuditEvent>> {
+        self.flush().await;
+
+        let data_dir = self.data_dir.clone();
+        tokio::task::spawn_blocking(move || load(&data_dir)).await?
+    }
+}
+
+/// Appends the events in the order they were recorded, until the [`AuditLog`] is dropped
+fn write_events(mut file: File, commands: mpsc::Receiver<Command>) {
+    for command in commands {
+        match command {
+            Command::Append(event) => {
+                let mut line = serde_json::to_vec(&event).expect("Events are always serializable");
+                line.push(b'\n');
+
+                // A single write per line, so a crash can at most truncate the last event
+                if let Err(err) = file.write_all(&line) {
+                    error!("could not append to the event log: {err}");
+                }
+            },
+            Command::Flush(done) => {
+                let _ = done.send(());
+            },
+        }
+    }
+}
+
+/// Length of `content` up to the end of the last complete line, every event is written with its line break at once
+fn complete_length(content: &[u8]) -> usize {
+    content.iter().rposition(|&byte| byte == b'\n').map_or(0, |index| index + 1)
+}
+
+/// Reads every event written by [`AuditLog::record`], oldest first.
+/// An incomplete last event, cut off by a crash, is skipped like [`AuditLog::open`] drops it.
+pub(crate) fn load(data_dir: &Path) -> io::Result<Vec<AuditEvent>> {
+    let content = match std::fs::read(data_dir.join(EVENTS_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let complete = complete_length(&content);
+    if complete < content.len() {
+        warn!("skipping the incomplete last event of the event log");
+    }
+
+    let mut events = Vec::new();
+    for (index, line) in content[..complete].lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue
+        }
+
+        let event = serde_json::from_str(&line)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {err}", index + 1)))?;
+        events.push(event);
+    }
+
+    Ok(events)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ReplayError {
+    #[error("the event log ends at version {0}")]
+    VersionNotReached(OrderStateVersion),
+    #[error("the event for version {0} does not apply to the replayed state, the log is incomplete")]
+    Diverged(OrderStateVersion),
+}
+
+/// Rebuilds the orders as they were at `version`
+pub(crate) fn replay(events: &[AuditEvent], version: OrderStateVersion, config: &Config) -> Result<OrderState, ReplayError> {
+    let mut state = OrderState::new(0);
+
+    for (index, event) in events.iter().enumerate() {
+        if event.version > version {
+            break
+        }
+
+        let applied = match event.change.clone() {
+            AuditChange::AddOrder(request) => state.try_add_order(request.name, request.order, config).is_some(),
+            AuditChange::EditOrder(request) |
+            AuditChange::RevertOrder(request) => state.try_edit_order(request.name, request.order, config).is_some(),
+            AuditChange::DeleteOrder(name) => state.try_delete_order(&name, config).is_some(),
+            AuditChange::Rollback(target) => {
+                // Versions only ever increase, so the target lies within the events before this one
+                let Ok(mut restored) = replay(&events[..index], target, config) else {
+                    return Err(ReplayError::Diverged(event.version))
+                };
+                restored.version = event.version;
+                restored.announcement = std::mem::take(&mut state.announcement);
+
+                state = restored;
+                true
+            },
+            AuditChange::SetAnnouncement(announcement) => {
+                state.announcement = announcement;
+                true
+            },
+        };
+
+        if !applied || state.version != event.version {
+            return Err(ReplayError::Diverged(event.version))
+        }
+    }
+
+    if state.version != version {
+        return Err(ReplayError::VersionNotReached(state.version))
+    }
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use pizza_bot_rs_common::orders::{Order, OrderRequest, PizzaKindArray};
+
+    use super::*;
+
+    fn event(version: OrderStateVersion, change: AuditChange) -> AuditEvent {
+        AuditEvent {
+            version,
+            timestamp_ms: 0,
+            address: SocketAddr::from(([127, 0, 0, 1], 0)),
+            user_agent: None,
+            by_organizer: false,
+            change,
+        }
+    }
+
+    fn request(name: &str, amounts: [usize; 3], preference: f32) -> OrderRequest {
+        OrderRequest {
+            name: name.to_owned(),
+            order: Order { amounts: PizzaKindArray(amounts), preference },
+        }
+    }
+
+    /// Names and amounts of all orders
+    fn orders(state: &OrderState) -> Vec<(&str, [usize; 3])> {
+        state.order_infos.iter().zip(&state.orders).map(|(info, order)| (info.name.as_str(), order.amounts.0)).collect()
+    }
+
+    fn events() -> Vec<AuditEvent> {
+        vec![
+            event(1, AuditChange::AddOrder(request("alice", [2, 0, 0], 0.5))),
+            event(2, AuditChange::AddOrder(request("bob", [0, 4, 0], 0.5))),
+            event(2, AuditChange::SetAnnouncement(String::from("order at 7"))),
+            event(3, AuditChange::EditOrder(request("alice", [4, 0, 0], 0.7))),
+            event(4, AuditChange::RevertOrder(request("alice", [2, 0, 0], 0.5))),
+            event(5, AuditChange::DeleteOrder(String::from("bob"))),
+        ]
+    }
+
+    #[test]
+    fn takes_the_client_address_from_trusted_proxies() {
+        let proxy = SocketAddr::from(([10, 0, 0, 1], 40000));
+        let trusted = [proxy.ip(), IpAddr::from([10, 0, 0, 2])];
+        let mut headers = HeaderMap::new();
+        // Forged by the client, then appended to by both proxies
+        headers.append("x-forwarded-for", "1.1.1.1, 192.0.2.7".parse().unwrap());
+        headers.append("x-forwarded-for", "10.0.0.2".parse().unwrap());
+
+        assert_eq!(client_address(proxy, &headers, &trusted), SocketAddr::from(([192, 0, 2, 7], 0)));
+        // Anyone else could have written the header themselves
+        let client = SocketAddr::from(([192, 0, 2, 7], 50000));
+        assert_eq!(client_address(client, &headers, &trusted), client);
+        assert_eq!(client_address(proxy, &HeaderMap::new(), &trusted), proxy);
+    }
+
+    #[test]
+    fn replays_every_kind_of_change() {
+        let config = Config::default();
+        let events = events();
+
+        let added = replay(&events, 2, &config).unwrap();
+        assert_eq!(orders(&added), [("alice", [2, 0, 0]), ("bob", [0, 4, 0])]);
+        assert_eq!(added.announcement, "order at 7");
+
+        let edited = replay(&events, 3, &config).unwrap();
+        assert_eq!(orders(&edited), [("alice", [4, 0, 0]), ("bob", [0, 4, 0])]);
+        assert_eq!(edited.orders[0].preference, 0.7);
+
+        let reverted = replay(&events, 4, &config).unwrap();
+        assert_eq!(orders(&reverted), [("alice", [2, 0, 0]), ("bob", [0, 4, 0])]);
+        assert_eq!(reverted.orders[0].preference, 0.5);
+
+        let deleted = replay(&events, 5, &config).unwrap();
+        assert_eq!(orders(&deleted), [("alice", [2, 0, 0])]);
+        assert_eq!(deleted.version, 5);
+    }
+
+    #[test]
+    fn replays_rollbacks_to_earlier_versions() {
+        let config = Config::default();
+        let mut events = events();
+        events.push(event(6, AuditChange::Rollback(3)));
+        events.push(event(7, AuditChange::DeleteOrder(String::from("alice"))));
+
+        let rolled_back = replay(&events, 6, &config).unwrap();
+        assert_eq!(orders(&rolled_back), [("alice", [4, 0, 0]), ("bob", [0, 4, 0])]);
+        assert_eq!(rolled_back.announcement, "order at 7");
+
+        assert_eq!(orders(&replay(&events, 7, &config).unwrap()), [("bob", [0, 4, 0])]);
+    }
+
+    #[test]
+    fn rejects_versions_the_log_does_not_reach() {
+        let config = Config::default();
+
+        assert!(matches!(replay(&events(), 6, &config), Err(ReplayError::VersionNotReached(5))));
+    }
+
+    #[test]
+    fn skips_an_incomplete_last_event() {
+        let data_dir = crate::persistence::temporary_data_dir("incomplete-event");
+        let mut content = Vec::new();
+        for event in events() {
+            content.extend(serde_json::to_vec(&event).unwrap());
+            content.push(b'\n');
+        }
+        // What a crash while appending the next event leaves behind
+        content.extend(br#"{"version":6,"timestamp_ms":0,"addr"#);
+        std::fs::write(data_dir.join(EVENTS_FILE), content).unwrap();
+
+        let events = load(&data_dir).unwrap();
+        assert_eq!(events.len(), 6);
+        assert_eq!(replay(&events, 5, &Config::default()).unwrap().version, 5);
+    }
+
+    #[test]
+    fn rejects_logs_with_repeated_versions() {
+        let config = Config::default();
+        // What a restart without the saved state used to append
+        let mut events = events();
+        events.push(event(1, AuditChange::AddOrder(request("carol", [1, 1, 1], 0.5))));
+
+        assert!(matches!(replay(&events, 5, &config), Err(ReplayError::Diverged(1))));
+    }
+}
@@ -2,6 +2,8 @@ use std::collections::BinaryHeap;
 
 use pizza_bot_rs_common::orders::{Distribution, Order, OrderAmount, PizzaAmount, PizzaKind, PizzaKindArray};
 
+use crate::config::BalancingConfig;
+
 type SumAmount = usize;
 type Penalty = f32;
 
@@ -24,10 +26,10 @@ fn calculate_cost(order: &Order, assigned: Distribution) -> Penalty {
             if scaled < 0.0 {
                 scaled = f32::INFINITY
             }
-            return scaled * p
+            scaled * p
         } else {
             let scaled = perc / (1.0 - perc).max(0.0);
-            return scaled * p
+            scaled * p
         }
     }
 
@@ -44,12 +46,14 @@ fn calculate_cost(order: &Order, assigned: Distribution) -> Penalty {
     let scaled_diffs = diffs.map(|d| d * shape_pref);
     let pens = diffs.zip_map(scaled_diffs, |d, s| if d < epsilon {d} else {s});
 
-    return total_penalty + 1.0 / (PizzaKind::Length as f32) * pens.sum::<Penalty>()
+    total_penalty + 1.0 / (PizzaKind::Length as f32) * pens.sum::<Penalty>()
 }
 
 pub struct TotalPenalty {
     worst: f32,
-    average: f32
+    average: f32,
+    /// Weight of `average` compared to `worst`
+    weight: f32
 }
 
 impl TotalPenalty {
@@ -76,16 +80,15 @@ impl TotalPenalty {
             return false
         }
 
-        return true
+        true
     }
 
     fn total(&self) -> f32 {
-        const weight: f32 = 0.1;
-        return (1.0 - weight) * self.worst + weight * self.average
+        (1.0 - self.weight) * self.worst + self.weight * self.average
     }
 }
 
-pub fn get_best(pieces_per_whole: OrderAmount, requests: &Vec<Order>) -> (TotalPenalty, PizzaKindArray<PizzaAmount>, Vec<Distribution>, bool) {
+pub fn get_best(pieces_per_whole: OrderAmount, config: &BalancingConfig, requests: &Vec<Order>) -> (TotalPenalty, PizzaKindArray<PizzaAmount>, Vec<Distribution>, bool) {
     let pieces_per_whole = pieces_per_whole as SumAmount;
     let mut totals: PizzaKindArray<SumAmount> = PizzaKindArray::splat(0);
     for req in requests {
@@ -133,11 +136,9 @@ pub fn get_best(pieces_per_whole: OrderAmount, requests: &Vec<Order>) -> (TotalP
                 }
             }
 
-            let Some(best) = best else {
-                return None
-            };
+            let best = best?;
 
-            return Some(Self {
+            Some(Self {
                 request_index: index,
                 offset: best,
                 penalty,
@@ -151,9 +152,10 @@ pub fn get_best(pieces_per_whole: OrderAmount, requests: &Vec<Order>) -> (TotalP
         }
     }
 
+    // Has to agree with `Ord`, since `BinaryHeap` uses both
     impl PartialOrd for QueueElement {
         fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-            other.penalty.partial_cmp(&self.penalty)
+            Some(self.cmp(other))
         }
     }
 
@@ -171,6 +173,7 @@ pub fn get_best(pieces_per_whole: OrderAmount, requests: &Vec<Order>) -> (TotalP
     let mut penalty = TotalPenalty {
         worst: f32::INFINITY,
         average: f32::INFINITY,
+        weight: config.average_weight as f32,
     };
 
     let mut next_distr = Vec::new();
@@ -209,6 +212,7 @@ pub fn get_best(pieces_per_whole: OrderAmount, requests: &Vec<Order>) -> (TotalP
         let mut pen = TotalPenalty {
             worst: 0.0,
             average: 0.0,
+            weight: config.average_weight as f32,
         };
 
         while deltas.sum::<SumAmount>() != 0 {
@@ -269,5 +273,5 @@ pub fn get_best(pieces_per_whole: OrderAmount, requests: &Vec<Order>) -> (TotalP
         config = PizzaKindArray::splat(0)
     }
 
-    return (penalty, config, best_distribution, is_valid)
+    (penalty, config, best_distribution, is_valid)
 }
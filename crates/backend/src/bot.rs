@@ -0,0 +1,427 @@
+//! Chat bot taking orders with commands like `!pizza 2 meat 1 vegan pref 0.7`
+//!
+//! Chat platforms are connected through a [`ChatAdapter`], the bot itself only sees users, names and lines of text.
+//! Orders go through the same [`AppState`](crate::state::AppState) methods as the WebSocket clients,
+//! and the bot posts the broadcast changes back to the chat.
+//! Rounds can not be locked yet, the bot instead posts once a round is full and takes no more orders.
+
+pub(crate) mod line;
+
+use std::{borrow::Cow, collections::HashMap, sync::Arc};
+
+use axum::async_trait;
+use pizza_bot_rs_common::{
+    communication::{DeleteOrderResponse, EditOrderResponse, EditToken, GetOrderResponse, MakeOrderResponse, OrderChange, Response},
+    export::ExportFormat,
+    orders::{Distribution, Order, OrderRequest, PizzaKind},
+    syntax, validation,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::{audit::Origin, persistence, state::{EncodedPackage, HandlerState, RoundChange}};
+
+const ORDERS_FILE: &str = "bot_orders.json";
+
+/// A line of text someone wrote in a chat the bot is in
+pub(crate) struct ChatMessage {
+    /// Stable ID of the sender on the platform, display names can be changed or taken by someone else
+    pub user_id: String,
+    /// Display name on the platform, used as the name of new orders
+    pub sender: String,
+    pub origin: Origin,
+    pub text: String,
+}
+
+/// Connection to a chat platform
+#[async_trait]
+pub(crate) trait ChatAdapter: Send + 'static {
+    /// Shown in the logs
+    fn name(&self) -> &'static str;
+
+    /// Whether the user IDs outlive a connection to the chat, otherwise the orders made in it are not saved,
+    /// since nobody could use them after a restart
+    fn has_stable_user_ids(&self) -> bool {
+        true
+    }
+
+    /// Waits for the next message, `None` once the platform is disconnected. Has to be cancel safe.
+    async fn receive(&mut self) -> Option<ChatMessage>;
+
+    /// Posts to the chat
+    async fn send(&mut self, text: &str);
+}
+
+/// Handles commands from `adapter` and posts the order changes to it until the server shuts down
+pub(crate) async fn run(state: HandlerState, mut adapter: impl ChatAdapter) {
+    let mut rx = state.broadcast.subscribe();
+    let mut shutdown = state.shutdown.subscribe();
+
+    let persistent = adapter.has_stable_user_ids();
+    let orders = match persistent.then(|| persistence::read_json(&state.config.data_dir, ORDERS_FILE)) {
+        Some(Ok(orders)) => orders.unwrap_or_default(),
+        Some(Err(err)) => {
+            warn!("could not restore the orders of the chat bot: {err}");
+            HashMap::new()
+        },
+        None => HashMap::new(),
+    };
+    let prefix = state.config.bot.prefix.clone();
+    let full = state.orders.lock().await.orders.len() >= state.config.limits.max_orders;
+    let mut bot = Bot { state, orders, persistent, full };
+    info!("chat bot connected to the {}", adapter.name());
+
+    loop {
+        // Commands and posts are only handled outside of the select, so a shutdown never aborts one halfway
+        let event = tokio::select! {
+            biased;
+            _ = shutdown.wait_for(|&shutdown| shutdown) => break,
+            message = adapter.receive() => Event::Message(message),
+            package = rx.recv() => Event::Broadcast(package),
+        };
+
+        match event {
+            Event::Message(Some(message)) => {
+                if let Some(reply) = bot.handle(&message).await {
+                    adapter.send(&format!("{}: {reply}", message.sender)).await;
+                }
+            },
+            Event::Message(None) => {
+                warn!("chat bot lost the connection to the {}", adapter.name());
+                break
+            },
+            Event::Broadcast(Ok(package)) => {
+                let Some(change) = package.change() else {
+                    continue
+                };
+                if let Some(text) = describe(change, &prefix) {
+                    adapter.send(&text).await;
+                }
+                if let Some(text) = bot.check_full().await {
+                    adapter.send(&text).await;
+                }
+            },
+            Event::Broadcast(Err(RecvError::Lagged(_))) => adapter.send(&format!("Missed some changes, `{prefix} summary` shows the current totals")).await,
+            Event::Broadcast(Err(RecvError::Closed)) => break,
+        }
+    }
+}
+
+enum Event {
+    Message(Option<ChatMessage>),
+    Broadcast(Result<Arc<EncodedPackage>, RecvError>),
+}
+
+/// An order made in the chat
+#[derive(Serialize, Deserialize)]
+struct ChatOrder {
+    name: String,
+    token: EditToken,
+}
+
+struct Bot {
+    state: HandlerState,
+    /// Orders made in the chat by the ID of the user who made them
+    orders: HashMap<String, ChatOrder>,
+    /// See [`ChatAdapter::has_stable_user_ids`]
+    persistent: bool,
+    /// Whether the round had reached `limits.max_orders` at the last change
+    full: bool,
+}
+
+fn help(prefix: &str) -> String {
+    format!(
+        "`{prefix} 2 meat, 1 vegan; pref 0.7` orders or changes your slices, the kinds are {}. \
+        `pref` goes from 0 for the shape of the pizza to 1 for the amount. \
+        `{prefix} show` shows your order, `{prefix} cancel` deletes it and `{prefix} summary` shows the totals.",
+        syntax::kind_names(),
+    )
+}
+
+impl Bot {
+    /// The reply to `message`, if it is a command
+    async fn handle(&mut self, message: &ChatMessage) -> Option<String> {
+        let arguments = message.text.trim().strip_prefix(&self.state.config.bot.prefix)?;
+        // Only the prefix as a whole word is a command
+        if arguments.starts_with(|c: char| !c.is_whitespace()) {
+            return None
+        }
+
+        let arguments: Vec<_> = arguments.split_whitespace().collect();
+        let reply = match arguments.as_slice() {
+            [] | ["help"] => help(&self.state.config.bot.prefix),
+            ["show"] => self.show(message).await,
+            ["cancel"] => self.cancel(message).await,
+            ["summary"] => self.state.export(ExportFormat::Text).await.trim_end().replace('\n', ", "),
+            arguments => match syntax::parse_order(&arguments.join(" ")) {
+                Ok(order) => self.order(message, order).await,
+                Err(err) => format!("{err}, see `{} help`", self.state.config.bot.prefix),
+            },
+        };
+
+        Some(reply)
+    }
+
+    /// Name of the order made by the sender of `message` in the chat, or else the one matching their display name
+    fn order_name(&self, message: &ChatMessage) -> String {
+        match self.orders.get(&message.user_id) {
+            Some(order) => order.name.clone(),
+            None => message.sender.clone(),
+        }
+    }
+
+    /// The post for a round that just became full, which is only posted once until orders are deleted again
+    async fn check_full(&mut self) -> Option<String> {
+        let count = self.state.orders.lock().await.orders.len();
+        let was_full = std::mem::replace(&mut self.full, count >= self.state.config.limits.max_orders);
+
+        (self.full && !was_full).then(|| format!("The round is full with {count} orders, no more orders are taken."))
+    }
+
+    async fn show(&self, message: &ChatMessage) -> String {
+        match self.state.get_order(&self.order_name(message)).await {
+            GetOrderResponse::Success(full) => format!(
+                "you ordered {} with preference {}, you get {}",
+                format_amounts(&full.order.amounts), full.order.preference, format_amounts(&full.distribution),
+            ),
+            GetOrderResponse::NameNotFound => String::from("you have not ordered yet"),
+        }
+    }
+
+    /// Edits the order the sender made in the chat, otherwise makes a new one with their display name
+    async fn order(&mut self, message: &ChatMessage, order: Order) -> String {
+        let origin = &message.origin;
+
+        if let Some(ChatOrder { name, token }) = self.orders.get(&message.user_id) {
+            let response = self.state.edit_order(OrderRequest { name: name.clone(), order }, Some(token), None, origin).await;
+            match response {
+                EditOrderResponse::Success => return format!("changed your order to {}", format_amounts(&order.amounts)),
+                EditOrderResponse::InvalidToken => return String::from("your order was changed outside of the chat, ask the organizer to change it"),
+                EditOrderResponse::Invalid(err) => return format!("invalid order, {err}"),
+                // Unreachable without an expected version
+                EditOrderResponse::Conflict(_) => return String::from("your order changed in the meantime, try again"),
+                // Deleted outside of the chat, so make a new one
+                EditOrderResponse::NameNotFound => {},
+            }
+        }
+
+        let Ok(name) = validation::normalize_name(&message.sender) else {
+            return String::from("your name can not be used for an order")
+        };
+
+        let response = match self.state.make_order_limited(OrderRequest { name: name.clone(), order }, None, origin).await {
+            Ok(Response::MakeOrder(response)) => response,
+            // Without a key nothing else can go wrong
+            _ => return String::from("too many new orders, try again later"),
+        };
+
+        match response {
+            MakeOrderResponse::Success(token) => {
+                self.orders.insert(message.user_id.clone(), ChatOrder { name, token });
+                self.save_orders();
+                format!("ordered {}", format_amounts(&order.amounts))
+            },
+            MakeOrderResponse::NameAlreadyRegistered => String::from("there already is an order with your name that you did not make here"),
+            MakeOrderResponse::TooManyOrders => String::from("the round is full"),
+            MakeOrderResponse::Invalid(err) => format!("invalid order, {err}"),
+        }
+    }
+
+    async fn cancel(&mut self, message: &ChatMessage) -> String {
+        let Some(ChatOrder { name, token }) = self.orders.get(&message.user_id) else {
+            return String::from("you have no order made in the chat")
+        };
+
+        let response = self.state.delete_order(name, Some(token), &message.origin).await;
+        let reply = match response {
+            DeleteOrderResponse::Success => "deleted your order",
+            DeleteOrderResponse::NameNotFound => "your order was already deleted",
+            DeleteOrderResponse::InvalidToken => return String::from("your order was changed outside of the chat, ask the organizer to delete it"),
+        };
+
+        self.orders.remove(&message.user_id);
+        self.save_orders();
+        reply.to_owned()
+    }
+
+    fn save_orders(&self) {
+        if !self.persistent {
+            return
+        }
+
+        if let Err(err) = persistence::write_json(&self.state.config.data_dir, ORDERS_FILE, &self.orders) {
+            tracing::error!("could not save the orders of the chat bot: {err}");
+        }
+    }
+}
+
+fn format_amounts(amounts: &Distribution) -> String {
+    let parts: Vec<_> = PizzaKind::All.into_iter()
+        .zip(amounts.0)
+        .filter(|&(_, amount)| amount > 0)
+        .map(|(kind, amount)| format!("{amount} {}", kind.name()))
+        .collect();
+
+    if parts.is_empty() {
+        return String::from("nothing")
+    }
+
+    parts.join(", ")
+}
+
+/// The chat post for a broadcast change, if it is worth one
+fn describe(change: &RoundChange, prefix: &str) -> Option<String> {
+    match change {
+        RoundChange::Update { change, config, distributions, distributions_valid } => {
+            let mut text = match change {
+                OrderChange::Set(full) => format!("{} ordered {}.", full.info.name, format_amounts(&full.order.amounts)),
+                OrderChange::Remove(name) => format!("{name} cancelled their order."),
+            };
+
+            if !distributions.is_empty() {
+                let pizzas: Vec<_> = PizzaKind::All.into_iter()
+                    .zip(config.0)
+                    .filter(|&(_, amount)| amount > 0)
+                    .map(|(kind, amount)| format!("{amount}x {}", kind.name()))
+                    .collect();
+                text.push_str(&format!(" Pizzas: {}.", if pizzas.is_empty() { Cow::Borrowed("none") } else { Cow::Owned(pizzas.join(", ")) }));
+
+                for change in distributions {
+                    text.push_str(&format!(" {} now gets {}.", change.name, format_amounts(&change.distribution)));
+                }
+            }
+            if !distributions_valid {
+                text.push_str(" The orders can not be split into whole pizzas yet.");
+            }
+
+            Some(text)
+        },
+        RoundChange::Reset(version) => Some(format!("The orders were reset to version {version}, `{prefix} summary` shows the new totals.")),
+        RoundChange::Announcement(announcement) if !announcement.is_empty() => Some(format!("Announcement: {announcement}")),
+        RoundChange::Announcement(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{io::AsyncWriteExt, net::tcp::OwnedReadHalf};
+
+    use crate::state::AppState;
+
+    use super::{line::{tests::{join, read_line}, LineChat}, *};
+
+    /// Runs the bot on a line chat for a fresh state
+    async fn start(test: &str) -> (HandlerState, LineChat) {
+        let state = Arc::new(AppState::temporary(test));
+        let chat = LineChat::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+
+        (state, chat)
+    }
+
+    /// Skips lines until one starts with `prefix`, every connection sees the posts and replies for everyone
+    async fn read_until(reader: &mut OwnedReadHalf, prefix: &str) -> String {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let line = read_line(reader).await;
+                if line.starts_with(prefix) {
+                    return line
+                }
+            }
+        }).await.unwrap_or_else(|_| panic!("no line starting with `{prefix}`"))
+    }
+
+    async fn amounts(state: &AppState, name: &str) -> Option<[usize; 3]> {
+        match state.get_order(name).await {
+            GetOrderResponse::Success(full) => Some(full.order.amounts.0),
+            GetOrderResponse::NameNotFound => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn orders_edits_and_cancels_from_the_chat() {
+        let (state, chat) = start("bot-orders").await;
+        let (mut reader, mut writer) = join(&chat, "alice").await;
+        tokio::spawn(run(state.clone(), chat));
+
+        writer.write_all(b"!pizza 2 meat 1 vegan pref 0.7\n").await.unwrap();
+        assert_eq!(read_until(&mut reader, "alice: ").await, "alice: ordered 2 meat, 1 vegan");
+        let GetOrderResponse::Success(full) = state.get_order("alice").await else {
+            panic!("the order was not made");
+        };
+        assert_eq!(full.order.amounts.0, [2, 0, 1]);
+        assert_eq!(full.order.preference, 0.7);
+        // The change is posted for everyone in the chat
+        assert!(read_until(&mut reader, "alice ordered ").await.starts_with("alice ordered 2 meat, 1 vegan."));
+
+        writer.write_all(b"!pizza 3 meat\n").await.unwrap();
+        assert_eq!(read_until(&mut reader, "alice: ").await, "alice: changed your order to 3 meat");
+        assert_eq!(amounts(&state, "alice").await, Some([3, 0, 0]));
+        assert!(read_until(&mut reader, "alice ordered ").await.starts_with("alice ordered 3 meat."));
+
+        writer.write_all(b"!pizza cancel\n").await.unwrap();
+        assert_eq!(read_until(&mut reader, "alice: ").await, "alice: deleted your order");
+        assert_eq!(amounts(&state, "alice").await, None);
+        assert_eq!(read_until(&mut reader, "alice cancelled").await, "alice cancelled their order.");
+    }
+
+    #[tokio::test]
+    async fn someone_else_with_the_same_name_can_not_take_over_an_order() {
+        let (state, chat) = start("bot-impostor").await;
+        let (mut alice_reader, mut alice) = join(&chat, "alice").await;
+        let (mut mallory_reader, mut mallory) = join(&chat, "alice").await;
+        tokio::spawn(run(state.clone(), chat));
+
+        alice.write_all(b"!pizza 2 meat\n").await.unwrap();
+        read_until(&mut alice_reader, "alice: ordered").await;
+        // Posted after the reply, so mallory only writes once the order is made
+        read_until(&mut mallory_reader, "alice ordered").await;
+
+        mallory.write_all(b"!pizza 5 vegan\n").await.unwrap();
+        assert_eq!(read_until(&mut mallory_reader, "alice: ").await, "alice: there already is an order with your name that you did not make here");
+        mallory.write_all(b"!pizza cancel\n").await.unwrap();
+        assert_eq!(read_until(&mut mallory_reader, "alice: ").await, "alice: you have no order made in the chat");
+
+        assert_eq!(amounts(&state, "alice").await, Some([2, 0, 0]));
+    }
+
+    #[tokio::test]
+    async fn posts_once_the_round_is_full() {
+        let mut state = AppState::temporary("bot-full");
+        state.config.limits.max_orders = 2;
+        let state = Arc::new(state);
+        let chat = LineChat::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let (mut alice_reader, mut alice) = join(&chat, "alice").await;
+        let (_bob_reader, mut bob) = join(&chat, "bob").await;
+        tokio::spawn(run(state.clone(), chat));
+
+        alice.write_all(b"!pizza 2 meat\n").await.unwrap();
+        read_until(&mut alice_reader, "alice ordered").await;
+        bob.write_all(b"!pizza 2 vegan\n").await.unwrap();
+        read_until(&mut alice_reader, "bob ordered").await;
+        assert_eq!(read_until(&mut alice_reader, "The round").await, "The round is full with 2 orders, no more orders are taken.");
+
+        // Edits of a full round are not announced again
+        alice.write_all(b"!pizza 3 meat\n").await.unwrap();
+        read_until(&mut alice_reader, "alice ordered 3").await;
+        alice.write_all(b"!pizza summary\n").await.unwrap();
+        // The next line after the posted edit is already the reply
+        let line = read_line(&mut alice_reader).await;
+        assert!(line.starts_with("alice: "), "{line}");
+    }
+
+    #[tokio::test]
+    async fn does_not_save_orders_of_connections() {
+        let (state, chat) = start("bot-unsaved").await;
+        let (mut reader, mut writer) = join(&chat, "alice").await;
+        tokio::spawn(run(state.clone(), chat));
+
+        writer.write_all(b"!pizza 2 meat\n").await.unwrap();
+        read_until(&mut reader, "alice: ordered").await;
+
+        // Connections get a new user ID every time, so saved orders could never be used again
+        assert!(!state.config.data_dir.join(ORDERS_FILE).exists());
+    }
+}
@@ -0,0 +1,219 @@
+//! A chat room over plain TCP, one message per line, for trying the bot with `nc` without a chat platform
+//!
+//! The first line of every connection is the name of the person, every following line is sent to the bot.
+//! Everything the bot posts is written to all connections.
+//! There are no accounts, so each connection is its own user and orders can only be changed from the connection that made them.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use axum::async_trait;
+use pizza_bot_rs_common::validation;
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, sync::{broadcast, mpsc}};
+use tracing::info;
+
+use crate::audit::Origin;
+
+use super::{ChatAdapter, ChatMessage};
+
+/// Messages buffered in either direction
+const CHANNEL_CAPACITY: usize = 64;
+
+pub(crate) struct LineChat {
+    address: SocketAddr,
+    incoming: mpsc::Receiver<ChatMessage>,
+    outgoing: broadcast::Sender<Arc<str>>,
+}
+
+impl LineChat {
+    pub async fn bind(address: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        let (incoming_tx, incoming) = mpsc::channel(CHANNEL_CAPACITY);
+        let (outgoing, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+        tokio::spawn(accept(listener, incoming_tx, outgoing.clone()));
+
+        Ok(Self { address, incoming, outgoing })
+    }
+
+    /// The bound address, with the actual port if it was bound to port 0
+    pub fn local_address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+#[async_trait]
+impl ChatAdapter for LineChat {
+    fn name(&self) -> &'static str {
+        "line chat"
+    }
+
+    /// Every connection is a new user
+    fn has_stable_user_ids(&self) -> bool {
+        false
+    }
+
+    async fn receive(&mut self) -> Option<ChatMessage> {
+        // The accept loop keeps a sender for as long as the listener is open
+        self.incoming.recv().await
+    }
+
+    async fn send(&mut self, text: &str) {
+        // Only fails if nobody is connected, in which case there is nobody to read it
+        let _ = self.outgoing.send(Arc::from(text));
+    }
+}
+
+async fn accept(listener: TcpListener, incoming: mpsc::Sender<ChatMessage>, outgoing: broadcast::Sender<Arc<str>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                tokio::spawn(handle_connection(stream, address, incoming.clone(), outgoing.subscribe()));
+            },
+            Err(err) => info!("could not accept chat connection: {err}"),
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, address: SocketAddr, incoming: mpsc::Sender<ChatMessage>, mut outgoing: broadcast::Receiver<Arc<str>>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let sender = loop {
+        if writer.write_all(b"Your name: ").await.is_err() {
+            return
+        }
+
+        let Ok(Some(line)) = lines.next_line().await else {
+            return
+        };
+
+        match validation::normalize_name(&line) {
+            Ok(name) => break name,
+            Err(err) => {
+                if writer.write_all(format!("Invalid name, {err}\n").as_bytes()).await.is_err() {
+                    return
+                }
+            },
+        }
+    };
+    info!("`{sender}` joined the line chat from {address}");
+    if writer.write_all(format!("Joined as {sender}\n").as_bytes()).await.is_err() {
+        return
+    }
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Ok(Some(text)) = line else {
+                    break
+                };
+
+                let message = ChatMessage {
+                    user_id: address.to_string(),
+                    sender: sender.clone(),
+                    origin: Origin {
+                        address,
+                        user_agent: Some(String::from("PizzaBot line chat")),
+                    },
+                    text,
+                };
+                if incoming.send(message).await.is_err() {
+                    break
+                }
+            },
+            text = outgoing.recv() => {
+                let text = match text {
+                    Ok(text) => text,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if writer.write_all(format!("{text}\n").as_bytes()).await.is_err() {
+                    break
+                }
+            },
+        }
+    }
+
+    info!("`{sender}` left the line chat");
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use tokio::{io::AsyncReadExt, net::tcp::{OwnedReadHalf, OwnedWriteHalf}};
+
+    use super::*;
+
+    /// Reads exactly `expected` from `reader`, which has no line break after the name prompt
+    pub(in crate::bot) async fn expect(reader: &mut OwnedReadHalf, expected: &str) {
+        let mut buffer = vec![0; expected.len()];
+        reader.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), expected);
+    }
+
+    /// Reads up to the next line break byte by byte, so nothing after it is buffered away
+    pub(in crate::bot) async fn read_line(reader: &mut OwnedReadHalf) -> String {
+        let mut line = Vec::new();
+        loop {
+            match reader.read_u8().await.unwrap() {
+                b'\n' => return String::from_utf8(line).unwrap(),
+                byte => line.push(byte),
+            }
+        }
+    }
+
+    /// Connects to `chat` and joins as `name`
+    pub(in crate::bot) async fn join(chat: &LineChat, name: &str) -> (OwnedReadHalf, OwnedWriteHalf) {
+        let (mut reader, mut writer) = TcpStream::connect(chat.local_address()).await.unwrap().into_split();
+        expect(&mut reader, "Your name: ").await;
+        writer.write_all(format!("{name}\n").as_bytes()).await.unwrap();
+        expect(&mut reader, &format!("Joined as {}\n", name.trim())).await;
+        (reader, writer)
+    }
+
+    #[tokio::test]
+    async fn asks_for_a_valid_name() {
+        let chat = LineChat::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let (mut reader, mut writer) = TcpStream::connect(chat.local_address()).await.unwrap().into_split();
+
+        expect(&mut reader, "Your name: ").await;
+        writer.write_all(b"\n").await.unwrap();
+        let line = read_line(&mut reader).await;
+        assert!(line.starts_with("Invalid name, "), "{line}");
+
+        expect(&mut reader, "Your name: ").await;
+        writer.write_all(b"  alice  \n").await.unwrap();
+        expect(&mut reader, "Joined as alice\n").await;
+    }
+
+    #[tokio::test]
+    async fn passes_messages_both_ways() {
+        let mut chat = LineChat::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let (mut reader, mut writer) = join(&chat, "alice").await;
+
+        writer.write_all(b"!pizza 2 meat\n").await.unwrap();
+        let message = chat.receive().await.unwrap();
+        assert_eq!(message.sender, "alice");
+        assert_eq!(message.text, "!pizza 2 meat");
+        assert_eq!(message.origin.address.ip(), chat.local_address().ip());
+
+        chat.send("hello").await;
+        expect(&mut reader, "hello\n").await;
+    }
+
+    #[tokio::test]
+    async fn tells_connections_with_the_same_name_apart() {
+        let mut chat = LineChat::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let (_alice_reader, mut alice) = join(&chat, "alice").await;
+        let (_mallory_reader, mut mallory) = join(&chat, "alice").await;
+
+        alice.write_all(b"!pizza cancel\n").await.unwrap();
+        let first = chat.receive().await.unwrap();
+        mallory.write_all(b"!pizza cancel\n").await.unwrap();
+        let second = chat.receive().await.unwrap();
+
+        assert_eq!(first.sender, second.sender);
+        assert_ne!(first.user_id, second.user_id);
+    }
+}
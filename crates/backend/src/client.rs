@@ -1,57 +1,191 @@
-use futures_util::{SinkExt, StreamExt};
-use pizza_bot_rs_common::{communication::{ClientPackage, EditOrderResponse, FullOrderData, GetOrderResponse, MakeOrderResponse, Response, ServerPackage}, orders::{Order, OrderAmount, OrderRequest, OrderState, PizzaKind, PizzaKindArray, Preference}};
-use tokio::{io::{AsyncBufReadExt, BufReader}, sync::Mutex};
-use std::{borrow::Cow, sync::Arc};
+use clap::Parser;
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use pizza_bot_rs_common::{audit::{AuditChange, AuditEvent}, communication::{ClientPackage, DeleteOrderResponse, EditOrderResponse, EditToken, GetOrderResponse, Hello, IdempotencyKey, IdentifyResponse, MakeOrderResponse, OrderChange, QueryEventsResponse, Request, RequestId, Response, RevertOrderResponse, RollbackResponse, ServerError, ServerPackage, SetAnnouncementResponse, PROTOCOL_VERSION}, encoding::Encoding, export::ExportFormat, orders::{OrderRequest, OrderState}, syntax, validation};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncBufReadExt, BufReader}, net::TcpStream, sync::{oneshot, Mutex}};
+use std::{borrow::Cow, collections::HashMap, path::PathBuf, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
 
 use tokio_tungstenite::{
     connect_async,
-    tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message},
+    MaybeTlsStream,
+    WebSocketStream,
+    tungstenite::{self, client::IntoClientRequest, http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue}, protocol::{frame::coding::CloseCode, CloseFrame, Message}},
 };
 
 const SERVER: &str = "ws://127.0.0.1:8081/ws";
 
+/// Locally stored secrets, kept in `$XDG_CONFIG_HOME/pizzabot/client.json` or `PIZZABOT_CLIENT_CONFIG`
+#[derive(Serialize, Deserialize, Default)]
+struct ClientConfig {
+    #[serde(default)]
+    organizer_token: Option<EditToken>,
+    /// Edit tokens of the orders made from this machine by name
+    #[serde(default)]
+    tokens: HashMap<String, EditToken>,
+}
+
+impl ClientConfig {
+    fn path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("PIZZABOT_CLIENT_CONFIG") {
+            return Some(PathBuf::from(path))
+        }
+
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+        Some(base.join("pizzabot").join("client.json"))
+    }
+
+    fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default()
+        };
+
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            return Self::default()
+        };
+
+        match serde_json::from_str(&content) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("\x1B[31m>>> Ignoring invalid config at {}: {err}\x1B[37m", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = Self::path() else {
+            println!("\x1B[31m>>> Could not determine config location, token is not stored\x1B[37m");
+            return
+        };
+
+        let result = path.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, serde_json::to_string_pretty(self).expect("Config is always serializable")));
+
+        if let Err(err) = result {
+            println!("\x1B[31m>>> Could not store config at {}: {err}\x1B[37m", path.display());
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "Command line client for the pizza bot backend")]
+struct Args {
+    /// Exchange MessagePack instead of JSON with the server
+    #[arg(long)]
+    msgpack: bool,
+}
+
 #[tokio::main]
 async fn main() {
-    spawn_client().await;
+    let args = Args::parse();
+    let encoding = if args.msgpack { Encoding::MessagePack } else { Encoding::Json };
+
+    spawn_client(encoding).await;
 }
 
-async fn spawn_client() {
-    let ws_stream = match connect_async(SERVER).await {
-        Ok((stream, _)) => stream,
-        Err(e) => {
-            println!("WebSocket handshake for client failed with {e}!");
-            return;
-        }
-    };
+fn encode_package(package: &impl Serialize, encoding: Encoding) -> Option<Message> {
+    let bytes = encoding.encode(package).ok()?;
 
-    let (rs, rr) = std::sync::mpsc::channel();
+    if encoding.is_binary() {
+        Some(Message::Binary(bytes))
+    } else {
+        String::from_utf8(bytes).ok().map(Message::Text)
+    }
+}
 
-    let (sender, mut receiver) = ws_stream.split();
+/// How long to wait for the response to a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 
-    struct Orders {
-        state: OrderState,
-        dirty: bool
+enum RequestError {
+    /// The connection is gone
+    Closed,
+    TimedOut,
+    /// The server answered with an error, which is printed when it arrives
+    Rejected,
+}
+
+/// Sending half of the connection, which matches responses to their requests by ID
+struct Connection {
+    sender: Mutex<SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>>,
+    encoding: Encoding,
+    next_id: AtomicU64,
+    /// Requests waiting for their response
+    pending: std::sync::Mutex<HashMap<RequestId, oneshot::Sender<Result<Response, ServerError>>>>,
+}
+
+impl Connection {
+    async fn send_request(&self, id: RequestId, idempotency_key: Option<IdempotencyKey>, package: ClientPackage) -> bool {
+        let Some(message) = encode_package(&Request { id, idempotency_key, package }, self.encoding) else {
+            println!("Could not create request");
+            return false
+        };
+
+        return self.sender.lock().await.send(message).await.is_ok()
     }
 
-    impl Orders {
-        fn print(&self) {
-            println!("config: {:?}, valid: {}", self.state.config.0, self.state.distributions_valid);
-            for ((info, order), distr) in self.state.order_infos.iter().zip(&self.state.orders).zip(&self.state.distributions) {
-                println!("{}: (amounts: {:?}, preference: {}), given: {:?}, price: {}, paid: {}", info.name, order.amounts.0, order.preference, distr.0, info.price.cents as f32 / 100 as f32, info.has_paid)
+    /// Sends the package without waiting for a response, `false` if the connection is gone
+    async fn send(&self, package: ClientPackage) -> bool {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        return self.send_request(id, None, package).await
+    }
+
+    /// Sends the package and waits for the response with the same ID
+    async fn request(&self, package: ClientPackage) -> Result<Response, RequestError> {
+        return self.request_idempotent(package, None).await
+    }
+
+    /// Like [`Connection::request`], but a repeated request with the same key gets the original response instead of running again
+    async fn request_idempotent(&self, package: ClientPackage, idempotency_key: Option<IdempotencyKey>) -> Result<Response, RequestError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+        // Registered before sending, so a fast response can not get lost
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+
+        if !self.send_request(id, idempotency_key, package).await {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(RequestError::Closed)
+        }
+
+        match tokio::time::timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(Ok(response))) => Ok(response),
+            Ok(Ok(Err(_))) => Err(RequestError::Rejected),
+            Ok(Err(_)) => Err(RequestError::Closed),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                println!("\x1B[31m>>> The server did not respond in time\x1B[37m");
+                Err(RequestError::TimedOut)
             }
         }
     }
 
-    let state;
+    /// Hands the result to the request waiting for it, `false` if there is none
+    fn resolve(&self, id: RequestId, result: Result<Response, ServerError>) -> bool {
+        let Some(tx) = self.pending.lock().unwrap().remove(&id) else {
+            return false
+        };
 
-    {
+        // The request only stops waiting after removing itself
+        let _ = tx.send(result);
+        true
+    }
+}
+
+/// Reads the content of the next package during the handshake, `None` if the connection is unusable
+async fn next_payload(receiver: &mut (impl StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin)) -> Option<Vec<u8>> {
+    loop {
         let Some(Ok(msg)) = receiver.next().await else {
-            return
+            return None
         };
 
-
-        let init = match msg {
-            Message::Text(t) => t,
+        match msg {
+            Message::Text(_) |
+            Message::Binary(_) => return Some(msg.into_data()),
             Message::Close(c) => {
                 if let Some(cf) = c {
                     println!(
@@ -62,97 +196,248 @@ async fn spawn_client() {
                     println!(">>> somehow got close message without CloseFrame");
                 }
 
-                return
+                return None
             },
 
-            Message::Binary(_) |
             Message::Pong(_) |
-            Message::Ping(_) => return,
+            Message::Ping(_) => continue,
 
             Message::Frame(_) => {
                 unreachable!("This is never supposed to happen")
             }
+        }
+    }
+}
+
+async fn spawn_client(encoding: Encoding) {
+    let Ok(mut request) = SERVER.into_client_request() else {
+        println!("Invalid server address {SERVER}");
+        return
+    };
+    request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(encoding.subprotocol()));
+
+    let ws_stream = match connect_async(request).await {
+        Ok((stream, _)) => stream,
+        Err(e) => {
+            println!("WebSocket handshake for client failed with {e}!");
+            return;
+        }
+    };
+
+    let (mut sender, mut receiver) = ws_stream.split();
+
+    struct Orders {
+        state: OrderState,
+        dirty: bool,
+        new_announcement: bool,
+        /// Names of the orders with an open connection
+        presence: Vec<String>,
+    }
+
+    impl Orders {
+        fn print_announcement(&self) {
+            if !self.state.announcement.is_empty() {
+                println!("\x1B[33m>>> Announcement: {}\x1B[37m", self.state.announcement);
+            }
+        }
+
+        fn print(&self) {
+            println!("config: {:?}, valid: {}", self.state.config.0, self.state.distributions_valid);
+            for ((info, order), distr) in self.state.order_infos.iter().zip(&self.state.orders).zip(&self.state.distributions) {
+                let online = if self.presence.binary_search(&info.name).is_ok() { ", online" } else { "" };
+                println!("{}: (amounts: {:?}, preference: {}), given: {:?}, price: {}, paid: {}{online}", info.name, order.amounts.0, order.preference, distr.0, info.price.cents as f32 / 100.0, info.has_paid)
+            }
+        }
+    }
+
+    let state;
+
+    {
+        let Some(init) = next_payload(&mut receiver).await else {
+            return
         };
 
-        let Ok(all) = serde_json::from_str::<FullOrderData>(&init) else {
-            // TODO handle malformed response
+        let server_hello = match encoding.decode::<ServerPackage>(&init) {
+            Ok(ServerPackage::Hello(hello)) => hello,
+            Ok(_) => {
+                println!("\x1B[31m>>> Server did not start with a handshake\x1B[37m");
+                return
+            },
+            Err(err) => {
+                println!("\x1B[31m>>> Received malformed handshake: {err}\x1B[37m");
+                return
+            }
+        };
+
+        if server_hello.protocol_version != PROTOCOL_VERSION {
+            println!("\x1B[31m>>> Server speaks protocol version {}, but this client only supports version {PROTOCOL_VERSION}\x1B[37m", server_hello.protocol_version);
+            return
+        }
+
+        let Some(message) = encode_package(&ClientPackage::Hello(Hello::current()), encoding) else {
+            println!("Could not create request");
+            return
+        };
+
+        if sender.send(message).await.is_err() {
+            return
+        }
+
+        let Some(init) = next_payload(&mut receiver).await else {
             return
         };
 
+        let all = match encoding.decode::<ServerPackage>(&init) {
+            Ok(ServerPackage::All(all)) => all,
+            Ok(ServerPackage::Error(error)) => {
+                println!("\x1B[31m>>> Server error ({:?}): {}\x1B[37m", error.code, error.message);
+                return
+            },
+            Ok(_) => {
+                println!("\x1B[31m>>> Server did not send its initial state\x1B[37m");
+                return
+            },
+            Err(err) => {
+                println!("\x1B[31m>>> Received malformed initial state: {err}\x1B[37m");
+                return
+            }
+        };
+
         state = Orders {
             state: OrderState::from_full_data(all),
             dirty: false,
+            new_announcement: false,
+            presence: Vec::new(),
         };
     }
 
+    let mut config = ClientConfig::load();
+
+    let connection = Arc::new(Connection {
+        sender: Mutex::new(sender),
+        encoding,
+        next_id: AtomicU64::new(0),
+        pending: std::sync::Mutex::new(HashMap::new()),
+    });
+
+    // Mark this connection as present for every order made from this machine, the answers are handled as they arrive
+    for (name, token) in &config.tokens {
+        if !connection.send(ClientPackage::Identify { name: name.clone(), token: token.clone() }).await {
+            return
+        }
+    }
+
+    state.print_announcement();
     println!("Orders:");
     state.print();
 
     let state = Arc::new(Mutex::new(state));
 
-    let sender = Arc::new(Mutex::new(sender));
-
     let mut recv_task = {
         let state = state.clone();
-        let sender = sender.clone();
+        let connection = connection.clone();
         tokio::spawn(async move {
             while let Some(Ok(msg)) = receiver.next().await {
                 match msg {
-                    Message::Text(t) => 'blk: {
-                        let Ok(response) = serde_json::from_str::<ServerPackage>(&t) else {
-                            // TODO handle malformed response
-                            break 'blk
+                    Message::Text(_) |
+                    Message::Binary(_) => 'blk: {
+                        let data = msg.into_data();
+                        let response = match encoding.decode::<ServerPackage>(&data) {
+                            Ok(response) => response,
+                            Err(err) => {
+                                println!("\x1B[31m>>> Received malformed message: {err}\x1B[37m");
+                                break 'blk
+                            }
                         };
 
                         match response {
-                            ServerPackage::Response(response) => {
-                                if rs.send(response).is_err() {
-                                    return
+                            // Identifications are sent without waiting for their answer
+                            ServerPackage::Response { response: Response::Identify(response), .. } => {
+                                match response {
+                                    IdentifyResponse::Success => {},
+                                    IdentifyResponse::NameNotFound => {},
+                                    IdentifyResponse::InvalidToken => println!("\x1B[31m>>> A stored edit token is no longer valid\x1B[37m"),
+                                }
+                            },
+                            ServerPackage::Response { id, response } => {
+                                if !connection.resolve(id, Ok(response)) {
+                                    println!("\x1B[31m>>> Ignoring response to request {id}, which timed out or was never sent\x1B[37m");
                                 }
                             },
-                            ServerPackage::Update { order, version, config, distributions, distributions_valid } => {
+                            ServerPackage::Update { change, version, config, distributions, distributions_valid } => {
                                 let mut state = state.lock().await;
 
                                 if state.state.version + 1 != version {
                                     drop(state);
 
-                                    let Ok(string) = serde_json::to_string(&ClientPackage::RequestAll) else {
-                                        println!("Could not create request");
-                                        break 'blk
-                                    };
-
-                                    if sender.lock().await
-                                        .send(Message::Text(string))
-                                        .await
-                                        .is_err()
-                                    {
-                                        break 'blk
-                                    }
-
+                                    // Answered with `All` instead of a response
+                                    connection.send(ClientPackage::RequestAll).await;
                                     break 'blk
                                 }
 
-                                match state.state.order_infos.binary_search_by(|info| info.name.cmp(&order.info.name)) {
-                                    Ok(index) => {
-                                        state.state.order_infos[index] = order.info;
-                                        state.state.orders[index] = order.order;
+                                match change {
+                                    OrderChange::Set(order) => {
+                                        match state.state.order_infos.binary_search_by(|info| info.name.cmp(&order.info.name)) {
+                                            Ok(index) => {
+                                                state.state.order_infos[index] = order.info;
+                                                state.state.orders[index] = order.order;
+                                                state.state.distributions[index] = order.distribution;
+                                            },
+                                            Err(index) => {
+                                                state.state.order_infos.insert(index, order.info);
+                                                state.state.orders.insert(index, order.order);
+                                                state.state.distributions.insert(index, order.distribution);
+                                            },
+                                        }
                                     },
-                                    Err(index) => {
-                                        state.state.order_infos.insert(index, order.info);
-                                        state.state.orders.insert(index, order.order);
+                                    OrderChange::Remove(name) => {
+                                        if let Ok(index) = state.state.order_infos.binary_search_by(|info| info.name.cmp(&name)) {
+                                            state.state.order_infos.remove(index);
+                                            state.state.orders.remove(index);
+                                            state.state.distributions.remove(index);
+                                        }
                                     },
                                 }
+                                for change in distributions {
+                                    if let Ok(index) = state.state.order_infos.binary_search_by(|info| info.name.cmp(&change.name)) {
+                                        state.state.distributions[index] = change.distribution;
+                                    }
+                                }
+                                state.state.version = version;
                                 state.state.config = config;
-                                state.state.distributions = distributions.into_owned();
                                 state.state.distributions_valid = distributions_valid;
                                 state.dirty = true;
                                 drop(state)
                             },
                             ServerPackage::All(all) => {
                                 let mut state = state.lock().await;
+                                if state.state.announcement != all.announcement {
+                                    state.new_announcement = true;
+                                }
                                 state.state = OrderState::from_full_data(all);
                                 state.dirty = true;
                                 drop(state)
+                            },
+                            ServerPackage::Announcement(announcement) => {
+                                let mut state = state.lock().await;
+                                state.state.announcement = announcement.into_owned();
+                                state.new_announcement = true;
+                                drop(state)
+                            },
+                            ServerPackage::Error(error) => {
+                                println!("\x1B[31m>>> Server error ({:?}): {}\x1B[37m", error.code, error.message);
+                                // Errors are sent instead of the response, so the pending request must not wait until it times out
+                                if let Some(id) = error.request {
+                                    connection.resolve(id, Err(error));
+                                }
+                            },
+                            ServerPackage::Presence(presence) => {
+                                let mut state = state.lock().await;
+                                state.presence = presence;
+                                drop(state)
+                            },
+                            ServerPackage::Hello(_) => {
+                                println!("\x1B[31m>>> Server repeated its handshake\x1B[37m");
                             }
                         }
                     },
@@ -169,7 +454,6 @@ async fn spawn_client() {
                         return
                     },
 
-                    Message::Binary(_) |
                     Message::Pong(_) |
                     Message::Ping(_) => {},
 
@@ -191,6 +475,10 @@ async fn spawn_client() {
         loop {
             {
                 let mut state = state.lock().await;
+                if state.new_announcement {
+                    state.print_announcement();
+                    state.new_announcement = false
+                }
                 if state.dirty {
                     println!("\x1B[36m>>> Orders have changed.\x1B[37m");
                     state.dirty = false
@@ -203,249 +491,498 @@ async fn spawn_client() {
             println!("(1) Make new order");
             println!("(2) Edit an order");
             println!("(3) Get an order");
+            println!("(4) Delete an order");
+            println!("(5) Undo the last edit of an order");
+            println!("(a) Set announcement");
+            println!("(b) Roll back all orders");
+            println!("(e) Show event log");
             println!("(v) View orders");
+            println!("(x) Export order summary");
             println!("(r) Reload");
             println!("(q) Exit");
             println!("------------------------------------");
 
-            loop {
-                buffer.clear();
-                let Ok(_) = input.read_line(&mut buffer).await else {
-                    break 'outer;
-                };
-
-                println!("\x1B[2J");
-                match buffer.trim() {
-                    "v" => {
-                        let mut state = state.lock().await;
-                        state.dirty = false;
-                        state.print();
-                        drop(state);
-                        println!();
-                        continue 'outer
-                    },
-                    "r" => continue 'outer,
-                    "1" => {
-                        let Some(mut request) = fun_name(&mut buffer, &mut input).await else {
+            buffer.clear();
+            let Ok(_) = input.read_line(&mut buffer).await else {
+                break 'outer;
+            };
+
+            println!("\x1B[2J");
+            match buffer.trim() {
+                "v" => {
+                    let mut state = state.lock().await;
+                    state.dirty = false;
+                    state.print();
+                    drop(state);
+                    println!();
+                    continue 'outer
+                },
+                "r" => continue 'outer,
+                "1" => {
+                    let Some(mut request) = fun_name(&mut buffer, &mut input).await else {
+                        break 'outer
+                    };
+
+                    let order = request.order;
+                    // Kept for retries, so an order that arrived although its response did not is not made twice
+                    let mut idempotency_key = generate_idempotency_key();
+
+                    loop {
+                        let name = request.name.clone();
+                        let response = connection.request_idempotent(ClientPackage::MakeOrder(request.clone()), Some(idempotency_key.clone())).await;
+                        if let Err(RequestError::Closed) = response {
                             break 'outer
-                        };
+                        }
 
-                        let order = request.order;
+                        if let Err(RequestError::TimedOut) = response {
+                            println!("Do you want to send the order again? (y/n):");
 
-                        loop {
-                            let Ok(string) = serde_json::to_string(&ClientPackage::MakeOrder(request)) else {
-                                println!("Could not create request");
-                                break 'outer
-                            };
+                            loop {
+                                buffer.clear();
+                                let Ok(_) = input.read_line(&mut buffer).await else {
+                                    break 'outer;
+                                };
 
-                            if sender.lock().await
-                                .send(Message::Text(string))
-                                .await
-                                .is_err()
-                            {
-                                break 'outer
-                            }
+                                match buffer.trim() {
+                                    "y" => break,
+                                    "n" => continue 'outer,
 
-                            let Ok(response) = rr.recv() else {
-                                break 'outer
-                            };
-
-                            let Response::MakeOrder(response) = response else {
-                                println!("Got invalid response try again later");
-                                break
-                            };
-
-                            match response {
-                                MakeOrderResponse::Success => println!("\x1B[32m>>> Request added successfully\x1B[37m"),
-                                MakeOrderResponse::NameAlreadyRegistered => {
-                                    println!("Name already exists. Do you want to try again? (y/n):");
-
-                                    loop {
-                                        buffer.clear();
-                                        let Ok(_) = input.read_line(&mut buffer).await else {
-                                            break 'outer;
-                                        };
-
-                                        match buffer.trim() {
-                                            "y" => break,
-                                            "n" => continue 'outer,
-
-                                            _ => {
-                                                println!("Invalid command");
-                                                continue
-                                            }
-                                        }
+                                    _ => {
+                                        println!("Invalid command");
+                                        continue
                                     }
+                                }
+                            }
 
-                                    println!("Type in a new name:");
+                            continue
+                        }
 
+                        let Ok(Response::MakeOrder(response)) = response else {
+                            println!("Got invalid response try again later");
+                            break
+                        };
+
+                        match response {
+                            MakeOrderResponse::Success(token) => {
+                                config.tokens.insert(name, token);
+                                config.save();
+                                println!("\x1B[32m>>> Request added successfully\x1B[37m")
+                            },
+                            MakeOrderResponse::Invalid(err) => println!("\x1B[31m>>> Invalid order: {err}\x1B[37m"),
+                            MakeOrderResponse::TooManyOrders => println!("\x1B[31m>>> The round already has the maximum number of orders\x1B[37m"),
+                            MakeOrderResponse::NameAlreadyRegistered => {
+                                println!("Name already exists. Do you want to try again? (y/n):");
+
+                                loop {
                                     buffer.clear();
                                     let Ok(_) = input.read_line(&mut buffer).await else {
                                         break 'outer;
                                     };
 
-                                    request = OrderRequest {
-                                        name: buffer.trim().to_owned(),
-                                        order,
-                                    };
-                                    continue
-                                },
-                            }
+                                    match buffer.trim() {
+                                        "y" => break,
+                                        "n" => continue 'outer,
 
-                            break
+                                        _ => {
+                                            println!("Invalid command");
+                                            continue
+                                        }
+                                    }
+                                }
+
+                                println!("Type in a new name:");
+
+                                buffer.clear();
+                                let Ok(_) = input.read_line(&mut buffer).await else {
+                                    break 'outer;
+                                };
+
+                                request = OrderRequest {
+                                    name: normalized_name(&buffer),
+                                    order,
+                                };
+                                idempotency_key = generate_idempotency_key();
+                                continue
+                            },
                         }
-                    },
-                    "2" => {
-                        let Some(mut request) = fun_name(&mut buffer, &mut input).await else {
+
+                        break
+                    }
+                },
+                "2" => {
+                    let Some(mut request) = fun_name(&mut buffer, &mut input).await else {
+                        break 'outer
+                    };
+
+                    let order = request.order;
+                    let mut conflict_version = None;
+
+                    loop {
+                        let Some(token) = resolve_token(&config, &request.name, &mut buffer, &mut input).await else {
                             break 'outer
                         };
 
-                        let order = request.order;
+                        // Based on the order as last seen here, unless the user chose to overwrite a newer one
+                        let expected_version = match conflict_version.take() {
+                            Some(version) => Some(version),
+                            None => {
+                                let state = state.lock().await;
+                                state.state.order_infos.iter()
+                                    .find(|info| info.name == request.name)
+                                    .map(|info| info.version)
+                            },
+                        };
 
-                        loop {
-                            let Ok(string) = serde_json::to_string(&ClientPackage::EditOrder(request)) else {
-                                println!("Could not create request");
-                                break 'outer
-                            };
+                        let response = connection.request(ClientPackage::EditOrder { request: request.clone(), token, expected_version }).await;
+                        if let Err(RequestError::Closed) = response {
+                            break 'outer
+                        }
 
-                            if sender.lock().await
-                                .send(Message::Text(string))
-                                .await
-                                .is_err()
-                            {
-                                break 'outer
-                            }
+                        let Ok(Response::EditOrder(response)) = response else {
+                            println!("Got invalid response try again later");
+                            break
+                        };
+
+                        match response {
+                            EditOrderResponse::Success => println!("\x1B[32m>>> Request edited successfully\x1B[37m"),
+                            EditOrderResponse::InvalidToken => println!("\x1B[31m>>> Invalid edit token\x1B[37m"),
+                            EditOrderResponse::Invalid(err) => println!("\x1B[31m>>> Invalid order: {err}\x1B[37m"),
+                            EditOrderResponse::Conflict(current) => {
+                                println!("\x1B[31m>>> The order was changed in the meantime, it is now (amounts: {:?}, preference: {})\x1B[37m", current.order.amounts.0, current.order.preference);
+                                println!("Do you want to overwrite it? (y/n):");
+
+                                loop {
+                                    buffer.clear();
+                                    let Ok(_) = input.read_line(&mut buffer).await else {
+                                        break 'outer;
+                                    };
+
+                                    match buffer.trim() {
+                                        "y" => break,
+                                        "n" => continue 'outer,
 
-                            let Ok(response) = rr.recv() else {
-                                break 'outer
-                            };
-
-                            let Response::EditOrder(response) = response else {
-                                println!("Got invalid response try again later");
-                                break
-                            };
-
-                            match response {
-                                EditOrderResponse::Success => println!("\x1B[32m>>> Request edited successfully\x1B[37m"),
-                                EditOrderResponse::NameNotFound => {
-                                    println!("Name does not exist. Do you want to try again? (y/n):");
-
-                                    loop {
-                                        buffer.clear();
-                                        let Ok(_) = input.read_line(&mut buffer).await else {
-                                            break 'outer;
-                                        };
-
-                                        match buffer.trim() {
-                                            "y" => break,
-                                            "n" => continue 'outer,
-
-                                            _ => {
-                                                println!("Invalid command");
-                                                continue
-                                            }
+                                        _ => {
+                                            println!("Invalid command");
+                                            continue
                                         }
                                     }
+                                }
 
-                                    println!("Type in a new name:");
+                                conflict_version = Some(current.info.version);
+                                continue
+                            },
+                            EditOrderResponse::NameNotFound => {
+                                println!("Name does not exist. Do you want to try again? (y/n):");
 
+                                loop {
                                     buffer.clear();
                                     let Ok(_) = input.read_line(&mut buffer).await else {
                                         break 'outer;
                                     };
 
-                                    request = OrderRequest {
-                                        name: buffer.trim().to_owned(),
-                                        order,
-                                    };
-                                    continue
-                                },
-                            }
+                                    match buffer.trim() {
+                                        "y" => break,
+                                        "n" => continue 'outer,
 
-                            break
+                                        _ => {
+                                            println!("Invalid command");
+                                            continue
+                                        }
+                                    }
+                                }
+
+                                println!("Type in a new name:");
+
+                                buffer.clear();
+                                let Ok(_) = input.read_line(&mut buffer).await else {
+                                    break 'outer;
+                                };
+
+                                request = OrderRequest {
+                                    name: normalized_name(&buffer),
+                                    order,
+                                };
+                                continue
+                            },
                         }
-                    },
-                    "3" => {
-                        println!("name: ");
 
-                        buffer.clear();
-                        let Ok(_) = input.read_line(&mut buffer).await else {
-                            break 'outer;
+                        break
+                    }
+                },
+                "3" => {
+                    println!("name: ");
+
+                    buffer.clear();
+                    let Ok(_) = input.read_line(&mut buffer).await else {
+                        break 'outer;
+                    };
+
+                    let mut name = normalized_name(&buffer);
+
+                    loop {
+                        let response = connection.request(ClientPackage::GetOrder(name)).await;
+                        if let Err(RequestError::Closed) = response {
+                            break 'outer
+                        }
+
+                        let Ok(Response::GetOrder(response)) = response else {
+                            println!("Got invalid response try again later");
+                            break
                         };
 
-                        let mut name = buffer.trim().to_owned();
+                        let order = match response {
+                            GetOrderResponse::Success(order) => order,
+                            GetOrderResponse::NameNotFound => {
+                                println!("Name does not exist. Do you want to try again? (y/n):");
 
-                        loop {
-                            let Ok(string) = serde_json::to_string(&ClientPackage::GetOrder(name)) else {
-                                println!("Could not create request");
-                                break 'outer
-                            };
+                                loop {
+                                    buffer.clear();
+                                    let Ok(_) = input.read_line(&mut buffer).await else {
+                                        break 'outer;
+                                    };
 
-                            if sender.lock().await
-                                .send(Message::Text(string))
-                                .await
-                                .is_err()
-                            {
-                                break 'outer
-                            }
+                                    match buffer.trim() {
+                                        "y" => break,
+                                        "n" => continue 'outer,
 
-                            let Ok(response) = rr.recv() else {
-                                break 'outer
-                            };
-
-                            let Response::GetOrder(response) = response else {
-                                println!("Got invalid response try again later");
-                                break
-                            };
-
-                            let order = match response {
-                                GetOrderResponse::Success(order) => order,
-                                GetOrderResponse::NameNotFound => {
-                                    println!("Name does not exist. Do you want to try again? (y/n):");
-
-                                    loop {
-                                        buffer.clear();
-                                        let Ok(_) = input.read_line(&mut buffer).await else {
-                                            break 'outer;
-                                        };
-
-                                        match buffer.trim() {
-                                            "y" => break,
-                                            "n" => continue 'outer,
-
-                                            _ => {
-                                                println!("Invalid command");
-                                                continue
-                                            }
+                                        _ => {
+                                            println!("Invalid command");
+                                            continue
                                         }
                                     }
+                                }
+
+                                println!("Type in a new name:");
+                                buffer.clear();
+                                let Ok(_) = input.read_line(&mut buffer).await else {
+                                    break 'outer;
+                                };
 
-                                    println!("Type in a new name:");
+                                name = normalized_name(&buffer);
+                                continue
+                            },
+                        };
+
+                        println!("{}: (amounts: {:?}, preference: {}), price: {}, paid: {}", order.info.name, order.order.amounts.0, order.order.preference, order.info.price.cents as f32 / 100.0, order.info.has_paid);
+
+                        break
+                    }
+                },
+                "4" => {
+                    println!("name: ");
+
+                    buffer.clear();
+                    let Ok(_) = input.read_line(&mut buffer).await else {
+                        break 'outer;
+                    };
+
+                    let mut name = normalized_name(&buffer);
+
+                    loop {
+                        let Some(token) = resolve_token(&config, &name, &mut buffer, &mut input).await else {
+                            break 'outer
+                        };
+
+                        let response = connection.request(ClientPackage::DeleteOrder { name: name.clone(), token }).await;
+                        if let Err(RequestError::Closed) = response {
+                            break 'outer
+                        }
+
+                        let Ok(Response::DeleteOrder(response)) = response else {
+                            println!("Got invalid response try again later");
+                            break
+                        };
+
+                        match response {
+                            DeleteOrderResponse::Success => {
+                                if config.tokens.remove(&name).is_some() {
+                                    config.save();
+                                }
+                                println!("\x1B[32m>>> Request deleted successfully\x1B[37m")
+                            },
+                            DeleteOrderResponse::InvalidToken => println!("\x1B[31m>>> Invalid edit token\x1B[37m"),
+                            DeleteOrderResponse::NameNotFound => {
+                                println!("Name does not exist. Do you want to try again? (y/n):");
+
+                                loop {
                                     buffer.clear();
                                     let Ok(_) = input.read_line(&mut buffer).await else {
                                         break 'outer;
                                     };
 
-                                    name = buffer.trim().to_owned();
-                                    continue
-                                },
-                            };
+                                    match buffer.trim() {
+                                        "y" => break,
+                                        "n" => continue 'outer,
+
+                                        _ => {
+                                            println!("Invalid command");
+                                            continue
+                                        }
+                                    }
+                                }
 
-                            println!("{}: (amounts: {:?}, preference: {}), price: {}, paid: {}", order.info.name, order.order.amounts.0, order.order.preference, order.info.price.cents as f32 / 100 as f32, order.info.has_paid);
+                                println!("Type in a new name:");
+                                buffer.clear();
+                                let Ok(_) = input.read_line(&mut buffer).await else {
+                                    break 'outer;
+                                };
 
-                            break
+                                name = normalized_name(&buffer);
+                                continue
+                            },
                         }
-                    },
-                    "q" => break 'outer,
 
-                    _ => println!("\x1B[31m>>> Invalid command\x1B[37m")
-                }
-                break
+                        break
+                    }
+                },
+                "5" => {
+                    println!("name: ");
+
+                    buffer.clear();
+                    let Ok(_) = input.read_line(&mut buffer).await else {
+                        break 'outer;
+                    };
+
+                    let name = normalized_name(&buffer);
+                    let Some(token) = resolve_token(&config, &name, &mut buffer, &mut input).await else {
+                        break 'outer
+                    };
+
+                    let response = connection.request(ClientPackage::RevertOrder { name, token }).await;
+                    if let Err(RequestError::Closed) = response {
+                        break 'outer
+                    }
+
+                    match response {
+                        Ok(Response::RevertOrder(RevertOrderResponse::Success(order))) => println!("\x1B[32m>>> Order reverted to (amounts: {:?}, preference: {})\x1B[37m", order.amounts.0, order.preference),
+                        Ok(Response::RevertOrder(RevertOrderResponse::NameNotFound)) => println!("\x1B[31m>>> Name does not exist\x1B[37m"),
+                        Ok(Response::RevertOrder(RevertOrderResponse::InvalidToken)) => println!("\x1B[31m>>> Invalid edit token\x1B[37m"),
+                        Ok(Response::RevertOrder(RevertOrderResponse::NothingToRevert)) => println!("\x1B[31m>>> The order has no earlier edits to undo\x1B[37m"),
+                        _ => println!("Got invalid response try again later"),
+                    }
+                },
+                "b" => {
+                    println!("version: ");
+
+                    buffer.clear();
+                    let Ok(_) = input.read_line(&mut buffer).await else {
+                        break 'outer;
+                    };
+
+                    let Ok(version) = buffer.trim().parse() else {
+                        println!("\x1B[31m>>> Invalid version\x1B[37m");
+                        continue 'outer
+                    };
+
+                    let Some(token) = organizer_token(&config, &mut buffer, &mut input).await else {
+                        break 'outer
+                    };
+
+                    let response = connection.request(ClientPackage::Rollback { version, token }).await;
+                    if let Err(RequestError::Closed) = response {
+                        break 'outer
+                    }
+
+                    match response {
+                        Ok(Response::Rollback(RollbackResponse::Success)) => println!("\x1B[32m>>> Orders rolled back to version {version}\x1B[37m"),
+                        Ok(Response::Rollback(RollbackResponse::InvalidToken)) => println!("\x1B[31m>>> Invalid organizer token\x1B[37m"),
+                        Ok(Response::Rollback(RollbackResponse::VersionNotFound)) => println!("\x1B[31m>>> Version {version} is not in the event log\x1B[37m"),
+                        Ok(Response::Rollback(RollbackResponse::Unavailable)) => println!("\x1B[31m>>> The server could not replay its event log\x1B[37m"),
+                        _ => println!("Got invalid response try again later"),
+                    }
+                },
+                "a" => {
+                    println!("announcement (empty to clear): ");
+
+                    buffer.clear();
+                    let Ok(_) = input.read_line(&mut buffer).await else {
+                        break 'outer;
+                    };
+
+                    let announcement = buffer.trim().to_owned();
+
+                    let Some(token) = organizer_token(&config, &mut buffer, &mut input).await else {
+                        break 'outer
+                    };
+
+                    let response = connection.request(ClientPackage::SetAnnouncement { announcement, token }).await;
+                    if let Err(RequestError::Closed) = response {
+                        break 'outer
+                    }
+
+                    match response {
+                        Ok(Response::SetAnnouncement(SetAnnouncementResponse::Success)) => println!("\x1B[32m>>> Announcement set successfully\x1B[37m"),
+                        Ok(Response::SetAnnouncement(SetAnnouncementResponse::InvalidToken)) => println!("\x1B[31m>>> Invalid organizer token\x1B[37m"),
+                        _ => println!("Got invalid response try again later"),
+                    }
+                },
+                "e" => {
+                    println!("name (empty for all orders): ");
+
+                    buffer.clear();
+                    let Ok(_) = input.read_line(&mut buffer).await else {
+                        break 'outer;
+                    };
+
+                    let name = Some(normalized_name(&buffer)).filter(|name| !name.is_empty());
+
+                    let Some(token) = organizer_token(&config, &mut buffer, &mut input).await else {
+                        break 'outer
+                    };
+
+                    let response = connection.request(ClientPackage::QueryEvents { name, token }).await;
+                    if let Err(RequestError::Closed) = response {
+                        break 'outer
+                    }
+
+                    match response {
+                        Ok(Response::QueryEvents(QueryEventsResponse::Success(events))) => {
+                            for event in &events {
+                                print_event(event);
+                            }
+                            println!("\x1B[32m>>> {} events\x1B[37m", events.len());
+                        },
+                        Ok(Response::QueryEvents(QueryEventsResponse::InvalidToken)) => println!("\x1B[31m>>> Invalid organizer token\x1B[37m"),
+                        Ok(Response::QueryEvents(QueryEventsResponse::Unavailable)) => println!("\x1B[31m>>> The server could not read its event log\x1B[37m"),
+                        _ => println!("Got invalid response try again later"),
+                    }
+                },
+                "x" => {
+                    println!("format ((t)ext, (c)sv, (m)arkdown, empty for text): ");
+
+                    buffer.clear();
+                    let Ok(_) = input.read_line(&mut buffer).await else {
+                        break 'outer;
+                    };
+
+                    let format = match buffer.trim() {
+                        "" | "t" => ExportFormat::Text,
+                        "c" => ExportFormat::Csv,
+                        "m" => ExportFormat::Markdown,
+                        _ => {
+                            println!("\x1B[31m>>> Invalid format\x1B[37m");
+                            continue 'outer
+                        }
+                    };
+
+                    let response = connection.request(ClientPackage::Export(format)).await;
+                    if let Err(RequestError::Closed) = response {
+                        break 'outer
+                    }
+
+                    match response {
+                        Ok(Response::Export(export)) => print!("{export}"),
+                        _ => println!("Got invalid response try again later"),
+                    }
+                },
+                "q" => break 'outer,
+
+                _ => println!("\x1B[31m>>> Invalid command\x1B[37m")
             }
         }
 
         println!("Terminating...");
-        if let Err(e) = sender.lock().await
+        if let Err(e) = connection.sender.lock().await
             .send(Message::Close(Some(CloseFrame {
                 code: CloseCode::Normal,
                 reason: Cow::from("Termination"),
@@ -462,65 +999,91 @@ async fn spawn_client() {
     };
 }
 
-async fn fun_name(buffer: &mut String, input: &mut BufReader<tokio::io::Stdin>) -> Option<OrderRequest> {
-    println!("name: ");
+fn generate_idempotency_key() -> IdempotencyKey {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn print_event(event: &AuditEvent) {
+    let by = if event.by_organizer { " by the organizer" } else { "" };
+    let change = match &event.change {
+        AuditChange::AddOrder(request) => format!("added {}: {:?}", request.name, request.order.amounts.0),
+        AuditChange::EditOrder(request) => format!("edited {}: {:?}", request.name, request.order.amounts.0),
+        AuditChange::DeleteOrder(name) => format!("deleted {name}"),
+        AuditChange::RevertOrder(request) => format!("reverted {}: {:?}", request.name, request.order.amounts.0),
+        AuditChange::Rollback(version) => format!("rolled back to v{version}"),
+        AuditChange::SetAnnouncement(announcement) => format!("announced `{announcement}`"),
+    };
+
+    println!("v{:<4} {} from {}{by}", event.version, change, event.address);
+}
+
+/// Looks up the stored edit token for `name`, falling back to the organizer token or asking the user
+async fn resolve_token(config: &ClientConfig, name: &str, buffer: &mut String, input: &mut BufReader<tokio::io::Stdin>) -> Option<EditToken> {
+    if let Some(token) = config.tokens.get(name).or(config.organizer_token.as_ref()) {
+        return Some(token.clone())
+    }
+
+    println!("edit token for `{name}`: ");
 
     buffer.clear();
     let Ok(_) = input.read_line(buffer).await else {
         return None
     };
 
-    let name = buffer.trim().to_owned();
+    Some(buffer.trim().to_owned())
+}
 
-    let mut amounts = PizzaKindArray::splat(0);
-    for i in 0..PizzaKind::Length {
-        println!("amount of type {i}: ");
+/// The organizer token from the config, or asks the user for it
+async fn organizer_token(config: &ClientConfig, buffer: &mut String, input: &mut BufReader<tokio::io::Stdin>) -> Option<EditToken> {
+    if let Some(token) = &config.organizer_token {
+        return Some(token.clone())
+    }
 
-        let amount = loop {
-            buffer.clear();
-            let Ok(_) = input.read_line(buffer).await else {
-                return None
-            };
+    println!("organizer token: ");
 
-            match buffer.trim().parse::<OrderAmount>() {
-                Ok(amount) => break amount,
-                Err(err) => {
-                    println!("Error {}", err);
-                    println!("Invalid input. Please input a non-negative integer: ");
-                    continue
-                }
-            }
-        };
+    buffer.clear();
+    let Ok(_) = input.read_line(buffer).await else {
+        return None
+    };
 
-        amounts.0[i] = amount
-    }
+    Some(buffer.trim().to_owned())
+}
 
-    println!("preference (from shape = 0 to amount = 1): ");
+/// Names are looked up in the normalized form, invalid ones are left to the server to reject
+fn normalized_name(input: &str) -> String {
+    validation::normalize_name(input).unwrap_or_else(|_| input.trim().to_owned())
+}
 
-    let preference = loop {
+async fn fun_name(buffer: &mut String, input: &mut BufReader<tokio::io::Stdin>) -> Option<OrderRequest> {
+    println!("name: ");
+
+    let name = loop {
         buffer.clear();
         let Ok(_) = input.read_line(buffer).await else {
             return None
         };
 
-        let Ok(preference) = buffer.trim().parse::<Preference>() else {
-            println!("Invalid input. Please input a number: ");
-            continue
-        };
-
-        if preference < 0.0 || preference > 1.0 {
-            println!("Invalid input. Must be in 0..1: ");
-            continue
+        match validation::normalize_name(buffer) {
+            Ok(name) => break name,
+            Err(err) => println!("Invalid input, {err}. Please input a name: "),
         }
-
-        break preference
     };
 
-    return Some(OrderRequest {
-        name,
-        order: Order {
-            amounts,
-            preference,
-        },
-    })
+    println!("order, for example `3 meat, 2 veg; pref 0.4` (kinds: {}, preference from shape = 0 to amount = 1): ", syntax::kind_names());
+
+    loop {
+        buffer.clear();
+        let Ok(_) = input.read_line(buffer).await else {
+            return None
+        };
+
+        match syntax::parse_request(&name, buffer) {
+            Ok(request) => return Some(request),
+            Err(err) => println!("Invalid input, {err}. Please try again: "),
+        }
+    }
 }
@@ -0,0 +1,392 @@
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, path::{Path, PathBuf}, time::Duration};
+
+use clap::Parser;
+use pizza_bot_rs_common::{communication::EditToken, globals::PizzaConfig, orders::{OrderStateVersion, Price}};
+use serde::{Deserialize, Serialize};
+
+/// Command line options, each of them overriding the respective entry of the config file
+#[derive(Parser)]
+#[command(version, about = "Backend for coordinating group pizza orders")]
+pub(crate) struct Cli {
+    /// TOML config file, missing entries fall back to their defaults
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Print the default config file and exit
+    #[arg(long)]
+    pub print_default_config: bool,
+
+    /// Print the orders at the given version, rebuilt from the event log, and exit
+    #[arg(long, value_name = "VERSION")]
+    pub replay: Option<OrderStateVersion>,
+
+    /// Address to listen on
+    #[arg(long)]
+    pub address: Option<IpAddr>,
+
+    /// Port to listen on
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Directory for persisted state
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Directory containing the built frontend
+    #[arg(long)]
+    pub frontend_dir: Option<PathBuf>,
+
+    /// Number of updates buffered per connection before it has to catch up with a full snapshot
+    #[arg(long)]
+    pub broadcast_capacity: Option<usize>,
+
+    /// Number of recent updates kept for event streams resuming with `Last-Event-ID`
+    #[arg(long)]
+    pub resume_capacity: Option<usize>,
+
+    /// Seconds between two pings sent to every connection
+    #[arg(long)]
+    pub heartbeat_interval: Option<u64>,
+
+    /// Seconds without any message after which a connection is closed
+    #[arg(long)]
+    pub heartbeat_timeout: Option<u64>,
+
+    /// Maximum number of orders in a single round
+    #[arg(long)]
+    pub max_orders: Option<usize>,
+
+    /// Log filter in `tracing_subscriber::EnvFilter` syntax, takes precedence over `RUST_LOG`
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Number of slices per whole pizza
+    #[arg(long)]
+    pub pieces_per_pizza: Option<u16>,
+
+    /// Price of a single slice in cents
+    #[arg(long)]
+    pub price_per_piece: Option<usize>,
+
+    /// Weight of the average penalty compared to the worst penalty when balancing, in 0..1
+    #[arg(long)]
+    pub average_weight: Option<f64>,
+
+    /// Address for the line based chat of the bot, which is disabled without it
+    #[arg(long)]
+    pub bot_address: Option<SocketAddr>,
+
+    /// Reverse proxy whose `X-Forwarded-For` header is trusted, can be given multiple times
+    #[arg(long = "trusted-proxy", value_name = "ADDRESS")]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub address: IpAddr,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub frontend_dir: PathBuf,
+    /// Number of updates buffered per connection before it has to catch up with a full snapshot
+    pub broadcast_capacity: usize,
+    /// Number of recent updates kept for event streams resuming with `Last-Event-ID`
+    pub resume_capacity: usize,
+    /// Used when `RUST_LOG` is not set
+    pub log_level: String,
+    /// Reverse proxies whose `X-Forwarded-For` header names the address of the client,
+    /// without them every client behind a proxy shares the rate limits of its address
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Falls back to `PIZZABOT_ORGANIZER_TOKEN` or a random token printed to stderr on startup, must not be empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub organizer_token: Option<EditToken>,
+    pub pizza: PizzaConfig,
+    pub balancing: BalancingConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub limits: LimitsConfig,
+    pub bot: BotConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BalancingConfig {
+    /// Weight of the average penalty compared to the worst penalty of a distribution
+    pub average_weight: f64,
+}
+
+/// Detection of dead connections
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HeartbeatConfig {
+    /// Seconds between two pings sent to every connection
+    pub interval: u64,
+    /// Seconds without any message, including pongs, after which a connection is closed
+    pub timeout: u64,
+}
+
+impl HeartbeatConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// Chat bot taking orders, see [`bot`](crate::bot)
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BotConfig {
+    /// Address of the line based chat, the bot is disabled without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_address: Option<SocketAddr>,
+    /// Word starting every command
+    pub prefix: String,
+}
+
+/// Protection against clients flooding the server
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LimitsConfig {
+    /// Messages a single connection, or mutating HTTP requests a single IP address, may send at once
+    pub message_burst: u32,
+    /// Messages a single connection, or mutating HTTP requests a single IP address, may send per minute on average
+    pub messages_per_minute: u32,
+    /// New orders per hour from a single IP address, over WebSocket, HTTP and the chat bot combined.
+    /// Everyone sharing an address, like an office behind a single NAT, shares this limit as well,
+    /// so raise it accordingly. Clients behind a reverse proxy are told apart with `trusted_proxies`.
+    pub orders_per_ip_per_hour: u32,
+    /// Orders in a single round
+    pub max_orders: usize,
+    /// Rate limited messages after which a connection is closed, one of them is forgiven every minute
+    pub max_violations: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8081,
+            data_dir: PathBuf::from("data"),
+            frontend_dir: PathBuf::from("crates/frontend/dist"),
+            broadcast_capacity: 16,
+            resume_capacity: 256,
+            log_level: String::from("debug,backend=debug,tower_http=off"),
+            trusted_proxies: Vec::new(),
+            organizer_token: None,
+            pizza: PizzaConfig::default(),
+            balancing: BalancingConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            limits: LimitsConfig::default(),
+            bot: BotConfig::default(),
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            message_burst: 20,
+            messages_per_minute: 120,
+            orders_per_ip_per_hour: 10,
+            max_orders: 100,
+            max_violations: 5,
+        }
+    }
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            line_address: None,
+            prefix: String::from("!pizza"),
+        }
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: 15,
+            timeout: 45,
+        }
+    }
+}
+
+impl Default for BalancingConfig {
+    fn default() -> Self {
+        Self {
+            average_weight: 0.1,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ConfigError {
+    #[error("could not read config file `{0}`: {1}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("invalid config file `{0}`: {1}")]
+    Parse(PathBuf, #[source] toml::de::Error),
+    #[error("invalid config: {0}")]
+    Invalid(&'static str),
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Read(path.to_owned(), err))?;
+
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    /// Loads the config file given on the command line and applies the remaining flags
+    pub fn from_cli(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::load(path)?,
+            None => Self::default(),
+        };
+
+        if config.organizer_token.is_none() {
+            config.organizer_token = std::env::var("PIZZABOT_ORGANIZER_TOKEN").ok()
+        }
+
+        if let Some(address) = cli.address {
+            config.address = address
+        }
+        if let Some(port) = cli.port {
+            config.port = port
+        }
+        if let Some(data_dir) = &cli.data_dir {
+            config.data_dir = data_dir.clone()
+        }
+        if let Some(frontend_dir) = &cli.frontend_dir {
+            config.frontend_dir = frontend_dir.clone()
+        }
+        if let Some(broadcast_capacity) = cli.broadcast_capacity {
+            config.broadcast_capacity = broadcast_capacity
+        }
+        if let Some(resume_capacity) = cli.resume_capacity {
+            config.resume_capacity = resume_capacity
+        }
+        if let Some(interval) = cli.heartbeat_interval {
+            config.heartbeat.interval = interval
+        }
+        if let Some(timeout) = cli.heartbeat_timeout {
+            config.heartbeat.timeout = timeout
+        }
+        if let Some(max_orders) = cli.max_orders {
+            config.limits.max_orders = max_orders
+        }
+        if let Some(log_level) = &cli.log_level {
+            config.log_level = log_level.clone()
+        }
+        if let Some(pieces_per_pizza) = cli.pieces_per_pizza {
+            config.pizza.pieces_per_pizza = pieces_per_pizza
+        }
+        if let Some(price_per_piece) = cli.price_per_piece {
+            config.pizza.price_per_piece = Price { cents: price_per_piece }
+        }
+        if let Some(average_weight) = cli.average_weight {
+            config.balancing.average_weight = average_weight
+        }
+        if let Some(bot_address) = cli.bot_address {
+            config.bot.line_address = Some(bot_address)
+        }
+        if !cli.trusted_proxies.is_empty() {
+            config.trusted_proxies = cli.trusted_proxies.clone()
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        // An empty token would match requests without any token
+        if self.organizer_token.as_ref().is_some_and(|token| token.trim().is_empty()) {
+            return Err(ConfigError::Invalid("`organizer_token` must not be empty"))
+        }
+        if self.broadcast_capacity == 0 {
+            return Err(ConfigError::Invalid("`broadcast_capacity` must be positive"))
+        }
+        if self.pizza.pieces_per_pizza == 0 {
+            return Err(ConfigError::Invalid("`pizza.pieces_per_pizza` must be positive"))
+        }
+        if !(0.0..=1.0).contains(&self.balancing.average_weight) {
+            return Err(ConfigError::Invalid("`balancing.average_weight` must be in 0..1"))
+        }
+        if self.heartbeat.interval == 0 {
+            return Err(ConfigError::Invalid("`heartbeat.interval` must be positive"))
+        }
+        if self.heartbeat.timeout <= self.heartbeat.interval {
+            return Err(ConfigError::Invalid("`heartbeat.timeout` must be longer than `heartbeat.interval`"))
+        }
+        if self.limits.message_burst == 0 || self.limits.messages_per_minute == 0 {
+            return Err(ConfigError::Invalid("`limits.message_burst` and `limits.messages_per_minute` must be positive"))
+        }
+        if self.limits.orders_per_ip_per_hour == 0 {
+            return Err(ConfigError::Invalid("`limits.orders_per_ip_per_hour` must be positive"))
+        }
+        if self.limits.max_orders == 0 {
+            return Err(ConfigError::Invalid("`limits.max_orders` must be positive"))
+        }
+        if self.limits.max_violations == 0 {
+            return Err(ConfigError::Invalid("`limits.max_violations` must be positive"))
+        }
+        if self.bot.prefix.is_empty() || self.bot.prefix.contains(char::is_whitespace) {
+            return Err(ConfigError::Invalid("`bot.prefix` must be a single word"))
+        }
+
+        Ok(())
+    }
+
+    pub fn listen_address(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejects(configure: impl FnOnce(&mut Config)) -> bool {
+        let mut config = Config::default();
+        configure(&mut config);
+        matches!(config.validate(), Err(ConfigError::Invalid(_)))
+    }
+
+    #[test]
+    fn accepts_the_defaults() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_an_empty_organizer_token() {
+        assert!(rejects(|config| config.organizer_token = Some(String::new())));
+        assert!(rejects(|config| config.organizer_token = Some(String::from(" \t"))));
+        assert!(!rejects(|config| config.organizer_token = Some(String::from("secret"))));
+    }
+
+    #[test]
+    fn rejects_zero_limits() {
+        assert!(rejects(|config| config.limits.message_burst = 0));
+        assert!(rejects(|config| config.limits.messages_per_minute = 0));
+        assert!(rejects(|config| config.limits.orders_per_ip_per_hour = 0));
+        assert!(rejects(|config| config.limits.max_orders = 0));
+        assert!(rejects(|config| config.limits.max_violations = 0));
+        assert!(rejects(|config| config.broadcast_capacity = 0));
+        assert!(rejects(|config| config.pizza.pieces_per_pizza = 0));
+        assert!(rejects(|config| config.heartbeat.interval = 0));
+    }
+
+    #[test]
+    fn rejects_a_timeout_not_longer_than_the_heartbeat() {
+        assert!(rejects(|config| config.heartbeat.timeout = config.heartbeat.interval));
+    }
+
+    #[test]
+    fn rejects_unknown_entries() {
+        assert!(toml::from_str::<Config>("port = 80\nprot = 81\n").is_err());
+        assert!(toml::from_str::<Config>("[limits]\nmax_orders = 5\n").is_ok_and(|config| config.limits.max_orders == 5));
+    }
+}
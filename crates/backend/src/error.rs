@@ -0,0 +1,88 @@
+use pizza_bot_rs_common::{communication::{close_code, ErrorCode, ProtocolVersion, RequestId, ServerError, ServerPackage, PROTOCOL_VERSION}, encoding::{DecodeError, Encoding}};
+
+/// Reasons for rejecting a message before it reaches the order logic
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ProtocolError {
+    #[error("malformed request: {0}")]
+    MalformedRequest(#[from] DecodeError),
+    #[error("the `{}` subprotocol expects {} messages", .0.subprotocol(), if .0.is_binary() { "binary" } else { "text" })]
+    WrongMessageType(Encoding),
+    #[error("expected `Hello` as first message")]
+    HandshakeExpected,
+    #[error("no `Hello` received in time")]
+    HandshakeTimeout,
+    #[error("handshake was already completed")]
+    RepeatedHandshake,
+    #[error("the server is shutting down")]
+    ShuttingDown,
+    #[error("protocol version {0} is not supported, the server speaks version {PROTOCOL_VERSION}")]
+    IncompatibleVersion(ProtocolVersion),
+    #[error("idempotency keys must be between 1 and 64 bytes long")]
+    InvalidIdempotencyKey,
+    #[error("the idempotency key was already used for a different request")]
+    IdempotencyKeyReused,
+    #[error("too many messages, slow down")]
+    MessageRateLimited,
+    #[error("too many new orders from this address")]
+    OrderRateLimited,
+    #[error("repeatedly exceeded the rate limits")]
+    TooManyViolations,
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProtocolError::MalformedRequest(_) |
+            ProtocolError::InvalidIdempotencyKey |
+            ProtocolError::IdempotencyKeyReused => ErrorCode::MalformedRequest,
+            ProtocolError::WrongMessageType(_) => ErrorCode::UnsupportedMessage,
+            ProtocolError::HandshakeExpected |
+            ProtocolError::HandshakeTimeout |
+            ProtocolError::RepeatedHandshake |
+            ProtocolError::ShuttingDown => ErrorCode::HandshakeFailed,
+            ProtocolError::IncompatibleVersion(_) => ErrorCode::IncompatibleVersion,
+            ProtocolError::MessageRateLimited |
+            ProtocolError::OrderRateLimited |
+            ProtocolError::TooManyViolations => ErrorCode::RateLimited,
+        }
+    }
+
+    /// The close code for errors after which the connection can not continue
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            ProtocolError::HandshakeExpected |
+            ProtocolError::HandshakeTimeout => Some(close_code::HANDSHAKE_FAILED),
+            ProtocolError::IncompatibleVersion(_) => Some(close_code::INCOMPATIBLE_VERSION),
+            ProtocolError::TooManyViolations => Some(axum::extract::ws::close_code::POLICY),
+            ProtocolError::ShuttingDown => Some(axum::extract::ws::close_code::AWAY),
+            ProtocolError::MalformedRequest(_) |
+            ProtocolError::InvalidIdempotencyKey |
+            ProtocolError::IdempotencyKeyReused |
+            ProtocolError::WrongMessageType(_) |
+            ProtocolError::RepeatedHandshake |
+            ProtocolError::MessageRateLimited |
+            ProtocolError::OrderRateLimited => None,
+        }
+    }
+
+    /// Whether the message was only rejected because the client sent too much
+    pub fn is_rate_limit(&self) -> bool {
+        matches!(self, ProtocolError::MessageRateLimited | ProtocolError::OrderRateLimited)
+    }
+
+    pub fn to_server_error(&self) -> ServerError {
+        ServerError {
+            code: self.code(),
+            request: None,
+            message: self.to_string(),
+        }
+    }
+
+    /// The error package in place of the response to `request`
+    pub fn to_package(&self, request: Option<RequestId>) -> ServerPackage<'static> {
+        ServerPackage::Error(ServerError {
+            request,
+            ..self.to_server_error()
+        })
+    }
+}
@@ -0,0 +1,81 @@
+//! Serves the compiled Yew frontend, as produced by `trunk build` in `crates/frontend`
+
+use std::path::Path;
+
+use axum::{
+    extract::Request, http::{header, HeaderValue}, middleware::{from_fn, Next}, response::Response, Router
+};
+use tower_http::services::{ServeDir, ServeFile};
+use tracing::warn;
+
+use crate::state::HandlerState;
+
+/// Every path not matched otherwise falls back to `index.html`, so the frontend can do its own routing
+pub(crate) fn router(dir: &Path) -> Router<HandlerState> {
+    let index = dir.join("index.html");
+    if !index.is_file() {
+        warn!("no frontend found at {}, build it with `trunk build` first", index.display());
+    }
+
+    Router::new()
+        .fallback_service(ServeDir::new(dir).fallback(ServeFile::new(index)))
+        .layer(from_fn(set_cache_control))
+}
+
+/// Whether the file name contains a hash of its content, like `frontend-0123456789abcdef_bg.wasm` from trunk
+fn is_fingerprinted(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let Some((_, rest)) = name.rsplit_once('-') else {
+        return false
+    };
+    let hash = rest.split(['.', '_']).next().unwrap_or(rest);
+
+    hash.len() == 16 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Fingerprinted assets never change, everything else like `index.html` or copied assets has to be revalidated on every load
+async fn set_cache_control(request: Request, next: Next) -> Response {
+    let fingerprinted = is_fingerprinted(request.uri().path());
+    let mut response = next.run(request).await;
+    if !response.status().is_success() {
+        return response
+    }
+
+    // Unknown paths fall back to `index.html`, which must not be cached under a fingerprinted name
+    let is_html = response.headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/html"));
+
+    let value = if fingerprinted && !is_html {
+        HeaderValue::from_static("public, max-age=31536000, immutable")
+    } else {
+        HeaderValue::from_static("no-cache")
+    };
+    response.headers_mut().insert(header::CACHE_CONTROL, value);
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_trunk_fingerprints() {
+        assert!(is_fingerprinted("/pizza-bot-rs-frontend-0123456789abcdef_bg.wasm"));
+        assert!(is_fingerprinted("/pizza-bot-rs-frontend-0123456789abcdef.js"));
+        assert!(is_fingerprinted("/styles-FEDCBA9876543210.css"));
+    }
+
+    #[test]
+    fn rejects_names_without_a_fingerprint() {
+        assert!(!is_fingerprinted("/index.html"));
+        assert!(!is_fingerprinted("/"));
+        assert!(!is_fingerprinted("/favicon.ico"));
+        // 16 characters, but not all of them hexadecimal
+        assert!(!is_fingerprinted("/pizza-bot-rs-frontend-0123456789abcdeg_bg.wasm"));
+        assert!(!is_fingerprinted("/menu-pizzeriadelcorso.png"));
+        // Hexadecimal, but not 16 characters
+        assert!(!is_fingerprinted("/pizza-bot-rs-frontend-0123456789abcde.js"));
+    }
+}
@@ -0,0 +1,277 @@
+//! Responses of recent mutating requests by idempotency key, so a retry after a dropped connection gets the original result
+//!
+//! Keys are scoped to the address of the client, so nobody else can fetch a remembered response, which may contain an edit token.
+
+use std::{collections::HashMap, future::Future, hash::{DefaultHasher, Hasher}, net::IpAddr, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use pizza_bot_rs_common::communication::{ClientPackage, IdempotencyKey, Response};
+use tokio::sync::OnceCell;
+
+use crate::error::ProtocolError;
+
+/// Longest accepted key in bytes
+const MAX_KEY_LENGTH: usize = 64;
+/// How long a response is remembered
+const KEY_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// Number of keys after which expired ones are forgotten
+const CLEANUP_THRESHOLD: usize = 1024;
+/// Number of keys after which the oldest one is forgotten, even if it did not expire yet
+const MAX_KEYS: usize = 4096;
+
+pub(crate) fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH
+}
+
+/// A request that may be retried, identified by its client and key, with a hash of its payload
+pub(crate) struct RequestKey {
+    client: IpAddr,
+    key: IdempotencyKey,
+    fingerprint: u64,
+}
+
+impl RequestKey {
+    /// HTTP requests pass the equivalent package, so a retry may switch between HTTP and WebSocket.
+    ///
+    /// The key only matches retries from the same `client` address. A client that changed its address in between,
+    /// like a phone switching from Wi-Fi to mobile data, runs the request again, which makes a duplicate edit
+    /// or fails a new order with [`MakeOrderResponse::NameAlreadyRegistered`](pizza_bot_rs_common::communication::MakeOrderResponse::NameAlreadyRegistered).
+    /// Matching by key and payload alone would hand the edit token of a new order to anyone who guesses the key,
+    /// since the payload is broadcast to everyone.
+    pub fn new(client: IpAddr, key: IdempotencyKey, package: &ClientPackage) -> Self {
+        let payload = serde_json::to_vec(package).expect("Client packages are always serializable");
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&payload);
+
+        Self {
+            client,
+            key,
+            fingerprint: hasher.finish(),
+        }
+    }
+}
+
+struct Entry {
+    created: Instant,
+    fingerprint: u64,
+    /// Empty while the original request is still running
+    response: Arc<OnceCell<Response>>,
+}
+
+#[derive(Default)]
+pub(crate) struct IdempotencyCache {
+    entries: Mutex<HashMap<(IpAddr, IdempotencyKey), Entry>>,
+}
+
+impl IdempotencyCache {
+    /// Runs `operation` and remembers its response under `key`, unless the key was used before,
+    /// in which case the remembered response is returned instead. Without a key the operation always runs.
+    /// A key used before for a different payload is rejected.
+    pub async fn run(&self, key: Option<RequestKey>, operation: impl Future<Output = Response>) -> Result<Response, ProtocolError> {
+        self.try_run(key, async { Ok(operation.await) }).await
+    }
+
+    /// Like [`run`](Self::run), but a failed operation is not remembered, so a retry runs it again.
+    /// A duplicate arriving while the operation runs waits for it either way.
+    pub async fn try_run(&self, key: Option<RequestKey>, operation: impl Future<Output = Result<Response, ProtocolError>>) -> Result<Response, ProtocolError> {
+        let Some(RequestKey { client, key, fingerprint }) = key else {
+            return operation.await
+        };
+
+        let response = {
+            let mut entries = self.entries.lock().unwrap();
+            let scoped = (client, key);
+
+            match entries.get(&scoped) {
+                Some(entry) if entry.created.elapsed() < KEY_LIFETIME => {
+                    if entry.fingerprint != fingerprint {
+                        return Err(ProtocolError::IdempotencyKeyReused)
+                    }
+
+                    entry.response.clone()
+                },
+                _ => {
+                    make_room(&mut entries);
+
+                    let response = Arc::new(OnceCell::new());
+                    entries.insert(scoped, Entry {
+                        created: Instant::now(),
+                        fingerprint,
+                        response: response.clone(),
+                    });
+                    response
+                },
+            }
+        };
+
+        // The lock is released, so other requests are not held up by the operation.
+        // A duplicate arriving meanwhile waits for the original, and only runs itself if the original was cancelled.
+        response.get_or_try_init(|| operation).await.cloned()
+    }
+
+    /// Forgets every finished response, since after a rollback they no longer describe the orders.
+    /// Running requests, including the rollback itself, still remember theirs.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().retain(|_, entry| !entry.response.initialized());
+    }
+}
+
+fn make_room(entries: &mut HashMap<(IpAddr, IdempotencyKey), Entry>) {
+    if entries.len() >= CLEANUP_THRESHOLD {
+        entries.retain(|_, entry| entry.created.elapsed() < KEY_LIFETIME);
+    }
+    if entries.len() >= MAX_KEYS {
+        let oldest = entries.iter()
+            .min_by_key(|(_, entry)| entry.created)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            entries.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use pizza_bot_rs_common::{communication::MakeOrderResponse, orders::{Order, OrderRequest, PizzaKindArray}};
+
+    use super::*;
+
+    const ALICE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+    const MALLORY: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+    const ALICE_ON_MOBILE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 3));
+
+    fn make_order(name: &str) -> ClientPackage {
+        ClientPackage::MakeOrder(OrderRequest {
+            name: name.to_owned(),
+            order: Order { amounts: PizzaKindArray([2, 0, 0]), preference: 0.5 },
+        })
+    }
+
+    fn key(client: IpAddr, package: &ClientPackage) -> Option<RequestKey> {
+        Some(RequestKey::new(client, String::from("key"), package))
+    }
+
+    /// Runs a request returning `token`, counting how often it actually ran
+    async fn run(cache: &IdempotencyCache, key: Option<RequestKey>, token: &str, runs: &AtomicUsize) -> Result<String, ProtocolError> {
+        let response = cache.run(key, async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            Response::MakeOrder(MakeOrderResponse::Success(token.to_owned()))
+        }).await?;
+
+        let Response::MakeOrder(MakeOrderResponse::Success(token)) = response else {
+            panic!("unexpected response");
+        };
+        Ok(token)
+    }
+
+    #[tokio::test]
+    async fn replays_the_original_response() {
+        let cache = IdempotencyCache::default();
+        let runs = AtomicUsize::new(0);
+        let package = make_order("alice");
+
+        assert_eq!(run(&cache, key(ALICE, &package), "first", &runs).await.unwrap(), "first");
+        assert_eq!(run(&cache, key(ALICE, &package), "second", &runs).await.unwrap(), "first");
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn duplicates_wait_for_the_running_original() {
+        let cache = IdempotencyCache::default();
+        let runs = AtomicUsize::new(0);
+        let package = make_order("alice");
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+
+        let original = cache.run(key(ALICE, &package), async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            let _ = released.await;
+            Response::MakeOrder(MakeOrderResponse::Success(String::from("first")))
+        });
+        let duplicate = run(&cache, key(ALICE, &package), "second", &runs);
+        let (original, duplicate, ()) = tokio::join!(original, duplicate, async {
+            tokio::task::yield_now().await;
+            let _ = release.send(());
+        });
+
+        assert!(matches!(original, Ok(Response::MakeOrder(MakeOrderResponse::Success(token))) if token == "first"));
+        assert_eq!(duplicate.unwrap(), "first");
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn runs_every_time_without_a_key() {
+        let cache = IdempotencyCache::default();
+        let runs = AtomicUsize::new(0);
+
+        assert_eq!(run(&cache, None, "first", &runs).await.unwrap(), "first");
+        assert_eq!(run(&cache, None, "second", &runs).await.unwrap(), "second");
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn forgets_failed_operations() {
+        let cache = IdempotencyCache::default();
+        let runs = AtomicUsize::new(0);
+        let package = make_order("alice");
+
+        let failed = cache.try_run(key(ALICE, &package), async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            Err(ProtocolError::OrderRateLimited)
+        }).await;
+        assert!(matches!(failed, Err(ProtocolError::OrderRateLimited)));
+
+        assert_eq!(run(&cache, key(ALICE, &package), "first", &runs).await.unwrap(), "first");
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_key_reused_for_another_payload() {
+        let cache = IdempotencyCache::default();
+        let runs = AtomicUsize::new(0);
+
+        run(&cache, key(ALICE, &make_order("alice")), "first", &runs).await.unwrap();
+        let reused = run(&cache, key(ALICE, &make_order("bob")), "second", &runs).await;
+
+        assert!(matches!(reused, Err(ProtocolError::IdempotencyKeyReused)));
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_responses_from_other_clients() {
+        let cache = IdempotencyCache::default();
+        let runs = AtomicUsize::new(0);
+        let package = make_order("alice");
+
+        assert_eq!(run(&cache, key(ALICE, &package), "alice's token", &runs).await.unwrap(), "alice's token");
+        assert_eq!(run(&cache, key(MALLORY, &package), "mallory's token", &runs).await.unwrap(), "mallory's token");
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn runs_retries_from_a_new_address_again() {
+        let cache = IdempotencyCache::default();
+        let runs = AtomicUsize::new(0);
+        let package = make_order("alice");
+
+        run(&cache, key(ALICE, &package), "first", &runs).await.unwrap();
+        assert_eq!(run(&cache, key(ALICE_ON_MOBILE, &package), "second", &runs).await.unwrap(), "second");
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+
+        // Each address keeps its own response
+        assert_eq!(run(&cache, key(ALICE, &package), "third", &runs).await.unwrap(), "first");
+        assert_eq!(run(&cache, key(ALICE_ON_MOBILE, &package), "third", &runs).await.unwrap(), "second");
+    }
+
+    #[tokio::test]
+    async fn forgets_responses_when_cleared() {
+        let cache = IdempotencyCache::default();
+        let runs = AtomicUsize::new(0);
+        let package = make_order("alice");
+
+        run(&cache, key(ALICE, &package), "first", &runs).await.unwrap();
+        cache.clear();
+
+        assert_eq!(run(&cache, key(ALICE, &package), "second", &runs).await.unwrap(), "second");
+    }
+}
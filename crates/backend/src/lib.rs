@@ -1,131 +1,179 @@
 #![allow(non_upper_case_globals)]
+mod api;
+mod audit;
 mod balancing;
+mod bot;
+mod config;
+mod error;
+mod frontend;
+mod idempotency;
+mod limits;
+mod metrics;
+mod persistence;
+mod presence;
+mod sse;
+mod state;
 
 use axum::{
     extract::{
-        connect_info::ConnectInfo, ws::{Message, WebSocket, WebSocketUpgrade}, Request, State
-    }, http::StatusCode, response::IntoResponse, routing::get, Router
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade}, State
+    }, response::IntoResponse, routing::get, Router
 };
-use axum_extra::TypedHeader;
-use futures::{stream::SplitSink, SinkExt, StreamExt};
-use pizza_bot_rs_common::{communication::{self, EditOrderResponse, GetOrderResponse, MakeOrderResponse, Response, ServerPackage}, orders::{FullOrder, Order, OrderInfo, OrderState, Price}};
-use tokio::sync::{broadcast, Mutex};
-use tracing::info;
-
-use std::{borrow::Cow, net::SocketAddr, sync::Arc};
+use audit::{AuditLog, Origin};
+use clap::Parser;
+use config::{Cli, Config};
+use error::ProtocolError;
+use limits::TokenBucket;
+use metrics::ConnectionGuard;
+use presence::ConnectionPresence;
+use futures::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt};
+use pizza_bot_rs_common::{communication::{self, Capability, Hello, IdentifyResponse, MakeOrderResponse, Response, ServerPackage, PROTOCOL_VERSION}, encoding::Encoding, orders::OrderState, validation};
+use idempotency::RequestKey;
+use state::{encode_message, generate_token, AppState, EncodedPackage, HandlerState};
+use tokio::sync::{broadcast::{self, error::RecvError}, watch, Mutex};
+use tracing::{info, warn};
+
+use std::{borrow::Cow, collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-trait OrderStateExt {
-    fn try_add_order(&mut self, name: String, order: Order) -> Option<FullOrder>;
-    fn try_edit_order(&mut self, name: String, order: Order) -> Option<FullOrder>;
-    fn finalize_update(&mut self);
-}
+pub async fn run() {
+    let cli = Cli::parse();
 
-impl OrderStateExt for OrderState {
-    fn try_add_order(&mut self, name: String, order: Order) -> Option<FullOrder> {
-        match self.order_infos.binary_search_by(|info| info.name.cmp(&name)) {
-            Ok(_) => return None,
-            Err(index) => {
-                let order = Order {
-                    preference: order.preference.clamp(0.0, 1.0),
-                    ..order
-                };
-                self.order_infos.insert(index, OrderInfo {
-                    name,
-                    has_paid: false,
-                    price: Price { cents: 0 },
-                });
-                self.orders.insert(index, order);
-
-                self.finalize_update();
-
-                Some(FullOrder {
-                    info: self.order_infos[index].clone(),
-                    order,
-                    distribution: self.distributions[index]
-                })
-            },
-        }
+    if cli.print_default_config {
+        print!("{}", toml::to_string_pretty(&Config::default()).expect("Default config is always serializable"));
+        return
     }
 
-    fn try_edit_order(&mut self, name: String, order: Order) -> Option<FullOrder> {
-        match self.order_infos.binary_search_by(|info| info.name.cmp(&name)) {
-            Ok(index) => {
-                let order = Order {
-                    preference: order.preference.clamp(0.0, 1.0),
-                    ..order
-                };
-                self.order_infos[index] = OrderInfo {
-                    name,
-                    has_paid: false,
-                    price: Price { cents: 0 },
-                };
-                self.orders[index] = order;
-
-                self.finalize_update();
-
-                Some(FullOrder {
-                    info: self.order_infos[index].clone(),
-                    order,
-                    distribution: self.distributions[index]
-                })
-            },
-            Err(_) => None
+    let config = match Config::from_cli(&cli) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1)
         }
-    }
+    };
 
-    fn finalize_update(&mut self) {
-        let (_, config, distributions, valid) = balancing::get_best(15, &self.orders);
+    // An explicit `--log-level` beats `RUST_LOG`, which beats the config file
+    let filter = match (&cli.log_level, tracing_subscriber::EnvFilter::try_from_default_env()) {
+        (None, Ok(filter)) => filter,
+        _ => config.log_level.as_str().into(),
+    };
 
-        self.config = config;
-        self.distributions = distributions;
-        self.distributions_valid = valid;
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
 
-        self.version += 1;
+    if let Err(err) = std::fs::create_dir_all(&config.data_dir) {
+        tracing::error!("could not create data directory {}: {err}", config.data_dir.display());
+        return
     }
-}
 
-struct AppState {
-    orders: Mutex<OrderState>,
-    broadcast: broadcast::Sender<String>
-}
+    if let Some(version) = cli.replay {
+        let state = audit::load(&config.data_dir)
+            .map_err(|err| err.to_string())
+            .and_then(|events| audit::replay(&events, version, &config).map_err(|err| err.to_string()));
 
-impl AppState {
-    pub fn new(broadcast: broadcast::Sender<String>) -> Self {
-        Self {
-            orders: Mutex::new(OrderState::new(0)),
-            broadcast
+        match state {
+            Ok(state) => println!("{}", serde_json::to_string_pretty(&state.to_full_data()).expect("Order state is always serializable")),
+            Err(err) => {
+                eprintln!("could not replay the event log: {err}");
+                std::process::exit(1)
+            }
         }
+        return
     }
-}
 
-type HandlerState = Arc<AppState>;
+    let audit = match AuditLog::open(&config.data_dir) {
+        Ok(audit) => audit,
+        Err(err) => {
+            tracing::error!("could not open the event log in {}: {err}", config.data_dir.display());
+            return
+        }
+    };
 
-pub async fn run() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "debug,backend=debug,tower_http=off".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let organizer_token = match config.organizer_token.clone() {
+        Some(token) => token,
+        None => {
+            let token = generate_token();
+            // Only on the terminal, so the secret does not end up in collected logs
+            eprintln!("organizer token is `{token}`");
+            token
+        }
+    };
 
-    let (tx, _) = broadcast::channel(16);
+    let (orders, tokens) = match persistence::load(&config.data_dir) {
+        Ok(Some((orders, tokens))) => {
+            info!("restored {} orders at version {}", orders.orders.len(), orders.version);
+            (orders, tokens)
+        },
+        Ok(None) => (OrderState::new(0), HashMap::new()),
+        Err(err) => {
+            tracing::error!("could not restore state from {}: {err}", config.data_dir.display());
+            return
+        }
+    };
+
+    let events = match audit::load(&config.data_dir) {
+        Ok(events) => events,
+        Err(err) => {
+            tracing::error!("could not read the event log in {}: {err}", config.data_dir.display());
+            return
+        }
+    };
+    // The state is only saved on shutdown, so after a crash the log is ahead of it and new events would repeat its versions
+    let logged = events.last().map_or(0, |event| event.version);
+    let orders = if logged > orders.version {
+        match audit::replay(&events, logged, &config) {
+            Ok(rebuilt) => {
+                // The tokens are saved on every new order, so they already belong to the rebuilt orders
+                warn!("the saved state ends at version {} but the event log at {logged}, rebuilt the orders from the log", orders.version);
+                rebuilt
+            },
+            Err(err) => {
+                tracing::error!("could not rebuild the orders from the event log: {err}");
+                return
+            }
+        }
+    } else {
+        if logged < orders.version {
+            warn!("the event log ends at version {logged} before the saved state at {}, later versions can not be replayed", orders.version);
+        }
+        orders
+    };
+
+    let (tx, _) = broadcast::channel(config.broadcast_capacity);
+
+    let address = config.listen_address();
+    let state = Arc::new(AppState::new(config, orders, tokens, tx, organizer_token, audit));
+
+    if let Some(bot_address) = state.config.bot.line_address {
+        match bot::line::LineChat::bind(bot_address).await {
+            Ok(chat) => {
+                info!("line chat listening on {}", chat.local_address());
+                tokio::spawn(bot::run(state.clone(), chat));
+            },
+            Err(err) => {
+                tracing::error!("could not open the line chat on {bot_address}: {err}");
+                return
+            }
+        }
+    }
 
     let app = Router::new()
-        .fallback(get(|_request: Request| async {
-            StatusCode::NOT_FOUND
-        }))
         .route("/ws", get(ws_handler))
-        .with_state(Arc::new(AppState::new(tx)))
+        .nest("/api", api::router())
+        .merge(metrics::router())
+        .merge(sse::router())
+        .merge(frontend::router(&state.config.frontend_dir))
+        .with_state(state.clone())
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::default().include_headers(true)),
         );
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8081")
+    let listener = tokio::net::TcpListener::bind(address)
         .await
         .unwrap();
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
@@ -133,201 +181,437 @@ pub async fn run() {
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(shutdown_signal(state.clone()))
     .await
     .unwrap();
+
+    // Upgraded connections are not tracked by axum, so wait for them to send their close frames
+    let open = state.shutdown.receiver_count();
+    if open != 0 {
+        info!("waiting for {open} connections to close");
+    }
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, state.shutdown.closed()).await.is_err() {
+        warn!("{} connections did not close in time", state.shutdown.receiver_count());
+    }
+
+    state.audit.flush().await;
+    match state.save().await {
+        Ok(()) => info!("state saved, exiting"),
+        Err(err) => tracing::error!("could not save state to {}: {err}", state.config.data_dir.display()),
+    }
+}
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves on Ctrl-C or SIGTERM and notifies all connections
+async fn shutdown_signal(state: HandlerState) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Could not install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Could not install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("shutting down");
+    state.shutdown.send_replace(true);
 }
 
 /// Upgrades a Websocket Connection
 async fn ws_handler(
     ws: WebSocketUpgrade,
-    user_agent: Option<TypedHeader<headers::UserAgent>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    origin: Origin,
     State(state): State<HandlerState>,
 ) -> impl IntoResponse {
-    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
-        user_agent.to_string()
-    } else {
-        String::from("Unknown browser")
-    };
-    info!("`{user_agent}` at {addr} connected.");
+    info!("`{}` at {} connected.", origin.user_agent.as_deref().unwrap_or("Unknown browser"), origin.address);
+
+    ws.protocols(Encoding::ALL.map(Encoding::subprotocol))
+        .on_upgrade(move |socket| {
+            // Clients that do not ask for a subprotocol get JSON
+            let encoding = socket.protocol()
+                .and_then(|protocol| protocol.to_str().ok())
+                .and_then(Encoding::from_subprotocol)
+                .unwrap_or_default();
 
-    ws.on_upgrade(move |socket| web_socket_thread(socket, addr, state))
+            web_socket_thread(socket, origin, encoding, state)
+        })
 }
 
-async fn send_serialized(message: impl serde::ser::Serialize, sender: &mut SplitSink<WebSocket, Message>) {
-    let Ok(string) = serde_json::to_string(&message) else {
-        // TODO handle, although currently the serializer should not be able to fail
-        panic!("Could not create response");
-    };
-    sender.send(Message::Text(string)).await.expect("Could not send message");
+async fn send_serialized(message: impl serde::ser::Serialize, encoding: Encoding, sender: &mut SplitSink<WebSocket, Message>) {
+    // A failed send means the connection is gone, which the receiving side notices on its own
+    let _ = sender.send(encode_message(&message, encoding)).await;
 }
 
-fn broadcast_serialized(message: impl serde::ser::Serialize, sender: &broadcast::Sender<String>) {
-    let Ok(string) = serde_json::to_string(&message) else {
-        // TODO handle, although currently the serializer should not be able to fail
-        panic!("Could not create response");
+/// Decodes a text or binary message, depending on which one the encoding uses
+fn decode_request<T: serde::de::DeserializeOwned>(message: &Message, encoding: Encoding) -> Result<T, ProtocolError> {
+    let bytes = match (message, encoding.is_binary()) {
+        (Message::Text(t), false) => t.as_bytes(),
+        (Message::Binary(b), true) => b.as_slice(),
+        _ => return Err(ProtocolError::WrongMessageType(encoding)),
     };
-    sender.send(string).expect("Could not send message");
+
+    Ok(encoding.decode(bytes)?)
+}
+
+/// The response to request `id`, or the error if it was rejected
+fn response_package(id: communication::RequestId, response: Result<Response, ProtocolError>) -> ServerPackage<'static> {
+    match response {
+        Ok(response) => ServerPackage::Response { id, response },
+        Err(err) => err.to_package(Some(id)),
+    }
+}
+
+/// Sends the error package followed by a close frame
+async fn close_with_error(err: &ProtocolError, encoding: Encoding, sender: &mut SplitSink<WebSocket, Message>) {
+    send_serialized(err.to_package(None), encoding, sender).await;
+    let _ = sender.send(Message::Close(Some(CloseFrame {
+        code: err.close_code().unwrap_or(close_code::PROTOCOL),
+        reason: Cow::Owned(err.to_string()),
+    }))).await;
+}
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time after which a single rate limit violation of a connection is forgiven
+const VIOLATION_DECAY: Duration = Duration::from_secs(60);
+
+/// Waits for the `Hello` of the client, `None` if the connection closed before
+async fn receive_hello(receiver: &mut SplitStream<WebSocket>, encoding: Encoding, shutdown: &mut watch::Receiver<bool>) -> Result<Option<Hello>, ProtocolError> {
+    loop {
+        let message = tokio::select! {
+            biased;
+            _ = shutdown.wait_for(|&shutdown| shutdown) => return Err(ProtocolError::ShuttingDown),
+            message = receiver.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return Ok(None),
+                Some(Ok(message)) => message,
+            },
+        };
+
+        match message {
+            Message::Text(_) | Message::Binary(_) => {
+                // Anything but a `Hello`, including malformed messages, means the client does not speak this protocol
+                let Ok(communication::ClientPackage::Hello(hello)) = decode_request(&message, encoding) else {
+                    return Err(ProtocolError::HandshakeExpected)
+                };
+
+                if hello.protocol_version != PROTOCOL_VERSION {
+                    return Err(ProtocolError::IncompatibleVersion(hello.protocol_version))
+                }
+
+                return Ok(Some(hello))
+            },
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) => continue,
+        }
+    }
 }
 
-async fn web_socket_thread(socket: WebSocket, who: SocketAddr, state: HandlerState) {
+async fn web_socket_thread(socket: WebSocket, origin: Origin, encoding: Encoding, state: HandlerState) {
+    let who = origin.address;
+    let _connection = ConnectionGuard::new(state.clone());
+    // Subscribed first, so the shutdown waits for this connection from the start
+    let mut shutdown = state.shutdown.subscribe();
     let (mut sender, mut receiver) = socket.split();
 
-    {   // Send initialize package
-        let orders = state.orders.lock().await;
+    send_serialized(ServerPackage::Hello(Hello::current()), encoding, &mut sender).await;
+
+    let result = tokio::time::timeout(HANDSHAKE_TIMEOUT, receive_hello(&mut receiver, encoding, &mut shutdown))
+        .await
+        .unwrap_or(Err(ProtocolError::HandshakeTimeout));
+
+    let hello = match result {
+        Ok(Some(hello)) => hello,
+        Ok(None) => return,
+        Err(err) => {
+            info!("handshake with {who} failed: {err}");
+
+            close_with_error(&err, encoding, &mut sender).await;
+            return
+        }
+    };
+    info!("{who} speaks protocol version {} with capabilities {:?} using {encoding:?}", hello.protocol_version, hello.capabilities);
+    // Packages of features the client did not declare are never sent to it
+    let capabilities = hello.capabilities.clone();
+    state.metrics.count_message(&communication::ClientPackage::Hello(hello));
+
+    let mut rx = {   // Send initialize package
+        let (orders, rx) = state.subscribe_with_snapshot().await;
         let init = orders.to_full_data();
 
-        send_serialized(&init, &mut sender).await;
+        send_serialized(ServerPackage::All(init), encoding, &mut sender).await;
+        rx
+    };
+    if capabilities.contains(&Capability::Presence) {
+        send_serialized(ServerPackage::Presence(state.presence.names()), encoding, &mut sender).await;
     }
 
     let sender = Arc::new(Mutex::new(sender));
 
-    let mut rx = state.broadcast.subscribe();
-
     // Send all broadcast through
     let mut send_task = {
         let sender = sender.clone();
+        let state = state.clone();
         tokio::spawn(async move {
-            while let Ok(msg) = rx.recv().await {
-                if sender.lock().await.send(Message::Text(msg)).await.is_err() {
+            let declared = |package: &EncodedPackage| package.required_capability().is_none_or(|capability| capabilities.contains(&capability));
+            let interval = state.config.heartbeat.interval();
+            let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+            loop {
+                let msg = tokio::select! {
+                    msg = rx.recv() => match msg {
+                        Ok(msg) if !declared(&msg) => continue,
+                        Ok(msg) => msg.message(encoding),
+                        Err(RecvError::Lagged(skipped)) => {
+                            let [all, presence] = state.resync(&mut rx, skipped, who).await;
+                            if sender.lock().await.send(all.message(encoding)).await.is_err() {
+                                break;
+                            }
+
+                            if !declared(&presence) {
+                                continue
+                            }
+                            presence.message(encoding)
+                        },
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = heartbeat.tick() => Message::Ping(Vec::new()),
+                };
+
+                if sender.lock().await.send(msg).await.is_err() {
                     break;
                 }
             }
         })
     };
 
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                // Entire communication over text, specifically common::ClientPackage/common::ServerPackage
-                Message::Text(t) => 'blk: {
-                    let Ok(request) = serde_json::from_str::<communication::ClientPackage>(&t) else {
-                        // TODO handle malformed request
-                        break 'blk
-                    };
-
-                    match request {
-                        communication::ClientPackage::MakeOrder(order) => {
-                            info!("`{}` made request `(amount: {:?}, preference: {})`", order.name, order.order.amounts.0, order.order.preference);
-
-                            let mut orders = state.orders.lock().await;
-                            let success = orders.try_add_order(order.name, order.order);
-
-                            let response = match success {
-                                Some(full) => {
-                                    broadcast_serialized(ServerPackage::Update {
-                                        order: full,
-                                        config: orders.config,
-
-                                        version: orders.version,
-                                        distributions: Cow::Borrowed(&orders.distributions),
-                                        distributions_valid: orders.distributions_valid,
-                                    }, &state.broadcast);
-                                    drop(orders);
-                                    MakeOrderResponse::Success
-                                },
-                                None => {
-                                    drop(orders);
-                                    MakeOrderResponse::NameAlreadyRegistered
-                                },
-                            };
-
-                            let mut sender = sender.lock().await;
-                            send_serialized(ServerPackage::Response(Response::MakeOrder(response)), &mut sender).await;
-                            drop(sender);
-                        },
-                        communication::ClientPackage::EditOrder(order) => {
-                            info!("Order edit for `{}` with `(amount: {:?}, preference: {})` requested", order.name, order.order.amounts.0, order.order.preference);
-
-                            let mut orders = state.orders.lock().await;
-                            let success = orders.try_edit_order(order.name, order.order);
-
-                            let response = match success {
-                                Some(full) => {
-                                    broadcast_serialized(ServerPackage::Update {
-                                        order: full,
-                                        config: orders.config,
-
-                                        version: orders.version,
-                                        distributions: Cow::Borrowed(&orders.distributions),
-                                        distributions_valid: orders.distributions_valid,
-                                    }, &state.broadcast);
-                                    drop(orders);
-                                    EditOrderResponse::Success
-                                },
-                                None => {
-                                    drop(orders);
-                                    EditOrderResponse::NameNotFound
-                                },
-                            };
-
-                            let mut sender = sender.lock().await;
-                            send_serialized(ServerPackage::Response(Response::EditOrder(response)), &mut sender).await;
-                            drop(sender);
-                        },
-                        communication::ClientPackage::GetOrder(name) => {
-                            info!("Order for `{name}` requested");
-
-                            let orders = state.orders.lock().await;
-                            let response = match orders.order_infos.binary_search_by(|info| info.name.cmp(&name)) {
-                                Ok(index) => {
-                                    let info = orders.order_infos[index].clone();
-                                    let order = orders.orders[index];
-                                    let distribution = orders.distributions[index];
-                                    drop(orders);
-
-                                    GetOrderResponse::Success(FullOrder {
-                                        info,
-                                        order,
-                                        distribution
-                                    })
-                                },
-                                Err(_) => {
-                                    drop(orders);
-
-                                    GetOrderResponse::NameNotFound
-                                },
-                            };
-
-                            let mut sender = sender.lock().await;
-                            send_serialized(ServerPackage::Response(Response::GetOrder(response)), &mut sender).await;
-                            drop(sender);
-                        },
-                        communication::ClientPackage::RequestAll => {
-                            info!("Full state requested");
+    let mut recv_task = {
+        let sender = sender.clone();
+        let mut shutdown = shutdown.clone();
+        let timeout = state.config.heartbeat.timeout();
+        tokio::spawn(async move {
+            // Released once the connection ends, even if this task is aborted
+            let mut presence = ConnectionPresence::new(state.clone());
+
+            let limits = &state.config.limits;
+            let mut messages = TokenBucket::new(limits.message_burst, limits.messages_per_minute as f64 / 60.0);
+            // Violations are forgiven over time, so only repeated offences in a short time close the connection
+            let mut violations = TokenBucket::new(limits.max_violations - 1, 1.0 / VIOLATION_DECAY.as_secs_f64());
+
+            // Requests are only interrupted between messages, so a shutdown never aborts one halfway
+            'receive:
+            while let Some(Ok(msg)) = tokio::select! {
+                biased;
+                _ = shutdown.wait_for(|&shutdown| shutdown) => None,
+                // Any message resets the timeout, usually the pong to one of the pings of the send task
+                msg = tokio::time::timeout(timeout, receiver.next()) => match msg {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        info!("{who} did not respond for {} seconds, closing connection", timeout.as_secs());
+                        None
+                    }
+                },
+            } {
+                match msg {
+                    // Entire communication as common::Request/common::ServerPackage in the negotiated encoding
+                    Message::Text(_) |
+                    Message::Binary(_) => 'blk: {
+                        // Taken before decoding, so malformed messages count as well
+                        let allowed = messages.try_take();
+
+                        let result = match decode_request(&msg, encoding) {
+                            Ok(communication::Request { id, idempotency_key, package }) => match package {
+                                _ if !allowed => Err((Some(id), ProtocolError::MessageRateLimited)),
+                                _ if idempotency_key.as_deref().is_some_and(|key| !idempotency::is_valid_key(key)) => Err((Some(id), ProtocolError::InvalidIdempotencyKey)),
+                                package => Ok((id, idempotency_key, package)),
+                            },
+                            Err(_) if !allowed => Err((None, ProtocolError::MessageRateLimited)),
+                            Err(err) => Err((None, err)),
+                        };
+
+                        let (id, idempotency_key, request) = match result {
+                            Ok((id, idempotency_key, request)) => {
+                                state.metrics.count_message(&request);
+                                (id, idempotency_key, request)
+                            },
+                            Err((id, err)) => {
+                                info!("{who} sent invalid message: {err}");
 
-                            {   // Send initialize package
-                                let orders = state.orders.lock().await;
-                                let init = orders.to_full_data();
+                                let mut sender = sender.lock().await;
+                                send_serialized(err.to_package(id), encoding, &mut sender).await;
+
+                                if err.is_rate_limit() && !violations.try_take() {
+                                    let err = ProtocolError::TooManyViolations;
+                                    info!("closing connection to {who}: {err}");
+
+                                    close_with_error(&err, encoding, &mut sender).await;
+                                    break 'receive
+                                }
+                                drop(sender);
+                                break 'blk
+                            }
+                        };
+
+                        let key = idempotency_key.map(|key| RequestKey::new(who.ip(), key, &request));
+                        match request {
+                            communication::ClientPackage::Hello(_) => {
+                                let err = ProtocolError::RepeatedHandshake;
+                                info!("{who} sent invalid message: {err}");
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(err.to_package(Some(id)), encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::MakeOrder(request) => {
+                                let name = validation::normalize_name(&request.name);
+                                // A retry joins the presence again, since it usually comes from a new connection
+                                let response = state.make_order_limited(request, key, &origin).await;
+                                if let (Ok(Response::MakeOrder(MakeOrderResponse::Success(_))), Ok(name)) = (&response, name) {
+                                    presence.join(name);
+                                }
+                                let violated = matches!(&response, Err(err) if err.is_rate_limit()) && !violations.try_take();
 
                                 let mut sender = sender.lock().await;
-                                send_serialized(&ServerPackage::All(init), &mut sender).await;
+                                send_serialized(response_package(id, response), encoding, &mut sender).await;
+                                if violated {
+                                    let err = ProtocolError::TooManyViolations;
+                                    info!("closing connection to {who}: {err}");
+
+                                    close_with_error(&err, encoding, &mut sender).await;
+                                    break 'receive
+                                }
+                                drop(sender);
+                            },
+                            communication::ClientPackage::EditOrder { request, token, expected_version } => {
+                                let response = state.idempotency.run(key, async {
+                                    Response::EditOrder(state.edit_order(request, Some(&token), expected_version, &origin).await)
+                                }).await;
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(response_package(id, response), encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::DeleteOrder { name, token } => {
+                                let response = state.idempotency.run(key, async {
+                                    Response::DeleteOrder(state.delete_order(&name, Some(&token), &origin).await)
+                                }).await;
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(response_package(id, response), encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::RevertOrder { name, token } => {
+                                let response = state.idempotency.run(key, async {
+                                    Response::RevertOrder(state.revert_order(&name, Some(&token), &origin).await)
+                                }).await;
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(response_package(id, response), encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::GetOrder(name) => {
+                                let response = state.get_order(&name).await;
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(ServerPackage::Response { id, response: Response::GetOrder(response) }, encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::RequestAll => {
+                                info!("Full state requested");
+
+                                {   // Send initialize package
+                                    let orders = state.orders.lock().await;
+                                    let init = orders.to_full_data();
+
+                                    let mut sender = sender.lock().await;
+                                    send_serialized(&ServerPackage::All(init), encoding, &mut sender).await;
+                                    drop(sender);
+                                }
+                            },
+                            communication::ClientPackage::SetAnnouncement { announcement, token } => {
+                                let response = state.idempotency.run(key, async {
+                                    Response::SetAnnouncement(state.set_announcement(announcement, Some(&token), &origin).await)
+                                }).await;
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(response_package(id, response), encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::Identify { name, token } => {
+                                let response = state.identify(&name, &token).await;
+                                if let (IdentifyResponse::Success, Ok(name)) = (&response, validation::normalize_name(&name)) {
+                                    presence.join(name);
+                                }
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(ServerPackage::Response { id, response: Response::Identify(response) }, encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::Rollback { version, token } => {
+                                let response = state.idempotency.run(key, async {
+                                    Response::Rollback(state.rollback(version, Some(&token), &origin).await)
+                                }).await;
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(response_package(id, response), encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::QueryEvents { name, token } => {
+                                let response = state.query_events(name.as_deref(), Some(&token)).await;
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(ServerPackage::Response { id, response: Response::QueryEvents(response) }, encoding, &mut sender).await;
+                                drop(sender);
+                            },
+                            communication::ClientPackage::Export(format) => {
+                                let export = state.export(format).await;
+
+                                let mut sender = sender.lock().await;
+                                send_serialized(ServerPackage::Response { id, response: Response::Export(export) }, encoding, &mut sender).await;
                                 drop(sender);
                             }
                         }
-                    }
-                },
-                Message::Close(c) => {
-                    if let Some(cf) = c {
-                        info!(
-                            "{} sent close with code {} and reason `{}`",
-                            who, cf.code, cf.reason
-                        );
-                    } else {
-                        info!("{who} somehow sent close message without CloseFrame");
-                    }
-                    break
-                },
+                    },
+                    Message::Close(c) => {
+                        if let Some(cf) = c {
+                            info!(
+                                "{} sent close with code {} and reason `{}`",
+                                who, cf.code, cf.reason
+                            );
+                        } else {
+                            info!("{who} somehow sent close message without CloseFrame");
+                        }
+                        break
+                    },
 
-                Message::Binary(_) |
-                Message::Pong(_) |
-                Message::Ping(_) => {}
+                    Message::Pong(_) |
+                    Message::Ping(_) => {}
+                }
             }
-        }
-    });
+        })
+    };
 
     tokio::select! {
         _ = &mut send_task => recv_task.abort(),
         _ = &mut recv_task => send_task.abort(),
     };
+
+    if *shutdown.borrow_and_update() {
+        info!("closing connection to {who} due to shutdown");
+        let _ = sender.lock().await.send(Message::Close(Some(CloseFrame {
+            code: close_code::AWAY,
+            reason: Cow::from("Server is shutting down"),
+        }))).await;
+    }
 }
@@ -0,0 +1,177 @@
+//! Rate limits against clients flooding the server, since every change rebalances all orders
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Instant};
+
+/// Allows bursts of up to `capacity` actions, refilling continuously at `per_second` actions per second
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, per_second: f64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            per_second,
+            tokens: capacity as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Consumes a token, returns `false` if none is left
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens < 1.0 {
+            return false
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Gives back a token taken for an action that turned out not to count
+    pub fn refund(&mut self) {
+        self.refill();
+        self.tokens = (self.tokens + 1.0).min(self.capacity);
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Number of addresses after which buckets that refilled completely are forgotten
+const IP_CLEANUP_THRESHOLD: usize = 1024;
+
+/// A [`TokenBucket`] per IP address, shared by all of its connections and HTTP requests
+pub(crate) struct IpLimits {
+    capacity: u32,
+    per_second: f64,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+impl IpLimits {
+    /// Every address gets a [`TokenBucket::new`] with these parameters
+    pub fn new(capacity: u32, per_second: f64) -> Self {
+        Self {
+            capacity,
+            per_second,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn try_take(&self, ip: IpAddr) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= IP_CLEANUP_THRESHOLD {
+            buckets.retain(|_, bucket| !bucket.is_full());
+        }
+
+        buckets.entry(ip)
+            .or_insert_with(|| TokenBucket::new(self.capacity, self.per_second))
+            .try_take()
+    }
+
+    /// See [`TokenBucket::refund`]
+    pub fn refund(&self, ip: IpAddr) {
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(&ip) {
+            bucket.refund();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(index: usize) -> IpAddr {
+        IpAddr::from((index as u32).to_be_bytes())
+    }
+
+    /// Pretends `elapsed` passed since the last refill
+    fn wait(bucket: &mut TokenBucket, elapsed: Duration) {
+        bucket.updated -= elapsed;
+    }
+
+    #[test]
+    fn allows_bursts_up_to_the_capacity() {
+        let mut bucket = TokenBucket::new(3, 0.0);
+
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = TokenBucket::new(2, 0.5);
+        while bucket.try_take() {}
+
+        wait(&mut bucket, Duration::from_secs(1));
+        assert!(!bucket.try_take());
+        wait(&mut bucket, Duration::from_secs(1));
+        assert!(bucket.try_take());
+
+        // Never beyond the capacity
+        wait(&mut bucket, Duration::from_secs(10));
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn refunds_up_to_the_capacity() {
+        let mut bucket = TokenBucket::new(2, 0.0);
+        assert!(bucket.try_take());
+
+        bucket.refund();
+        bucket.refund();
+        assert!(bucket.is_full());
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn limits_every_address_on_its_own() {
+        let limits = IpLimits::new(1, 0.0);
+
+        assert!(limits.try_take(ip(1)));
+        assert!(!limits.try_take(ip(1)));
+        assert!(limits.try_take(ip(2)));
+
+        limits.refund(ip(1));
+        assert!(limits.try_take(ip(1)));
+    }
+
+    #[test]
+    fn forgets_only_full_buckets_on_cleanup() {
+        let limits = IpLimits::new(2, 0.0);
+        for index in 0..IP_CLEANUP_THRESHOLD {
+            assert!(limits.try_take(ip(index)));
+            if index % 2 == 0 {
+                limits.refund(ip(index));
+            }
+        }
+
+        assert!(limits.try_take(ip(IP_CLEANUP_THRESHOLD)));
+        assert_eq!(limits.buckets.lock().unwrap().len(), IP_CLEANUP_THRESHOLD / 2 + 1);
+
+        // The others keep what they used up
+        assert!(limits.try_take(ip(1)));
+        assert!(!limits.try_take(ip(1)));
+    }
+}
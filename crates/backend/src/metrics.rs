@@ -0,0 +1,239 @@
+//! Prometheus metrics in the text exposition format, and health checks for process supervisors
+
+use std::{borrow::Cow, collections::BTreeMap, fmt::Write, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Mutex}, time::Duration};
+
+use axum::{
+    extract::State, http::{header, StatusCode}, response::{IntoResponse, Response}, routing::get, Router
+};
+use pizza_bot_rs_common::communication::ClientPackage;
+
+use crate::state::HandlerState;
+
+/// Upper bounds of the rebalance histogram buckets in seconds
+const REBALANCE_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Default)]
+pub(crate) struct Histogram {
+    /// Observations per bucket, the last one counts everything above the largest bound
+    buckets: [AtomicU64; REBALANCE_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let index = REBALANCE_BUCKETS.iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(REBALANCE_BUCKETS.len());
+
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in REBALANCE_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            write_labeled(out, &format!("{name}_bucket"), "le", &bound.to_string(), cumulative);
+        }
+        cumulative += self.buckets[REBALANCE_BUCKETS.len()].load(Ordering::Relaxed);
+
+        write_labeled(out, &format!("{name}_bucket"), "le", "+Inf", cumulative);
+        let _ = writeln!(out, "{name}_sum {}", self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9);
+        let _ = writeln!(out, "{name}_count {cumulative}");
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Currently open WebSocket connections
+    pub connections: AtomicUsize,
+    /// Received packages by variant
+    messages: Mutex<BTreeMap<&'static str, u64>>,
+    /// Duration of rebalancing all orders after a change
+    pub rebalance: Histogram,
+}
+
+impl Metrics {
+    pub fn count_message(&self, package: &ClientPackage) {
+        let variant = match package {
+            ClientPackage::Hello(_) => "Hello",
+            ClientPackage::MakeOrder(_) => "MakeOrder",
+            ClientPackage::EditOrder { .. } => "EditOrder",
+            ClientPackage::DeleteOrder { .. } => "DeleteOrder",
+            ClientPackage::RevertOrder { .. } => "RevertOrder",
+            ClientPackage::GetOrder(_) => "GetOrder",
+            ClientPackage::RequestAll => "RequestAll",
+            ClientPackage::SetAnnouncement { .. } => "SetAnnouncement",
+            ClientPackage::Identify { .. } => "Identify",
+            ClientPackage::Rollback { .. } => "Rollback",
+            ClientPackage::QueryEvents { .. } => "QueryEvents",
+            ClientPackage::Export(_) => "Export",
+        };
+
+        *self.messages.lock().unwrap().entry(variant).or_insert(0) += 1;
+    }
+}
+
+/// Counts a connection as open until it is dropped
+pub(crate) struct ConnectionGuard(HandlerState);
+
+impl ConnectionGuard {
+    pub fn new(state: HandlerState) -> Self {
+        state.metrics.connections.fetch_add(1, Ordering::Relaxed);
+        Self(state)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn router() -> Router<HandlerState> {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+/// Escapes `\\` and line breaks, and in label values `"` as well, as the text exposition format requires
+fn escape(text: &str, is_label_value: bool) -> Cow<'_, str> {
+    if !text.contains(['\\', '\n', '"']) {
+        return Cow::Borrowed(text)
+    }
+
+    let mut escaped = String::with_capacity(text.len() + 2);
+    for char in text.chars() {
+        match char {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if is_label_value => escaped.push_str("\\\""),
+            char => escaped.push(char),
+        }
+    }
+
+    Cow::Owned(escaped)
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {}", escape(help, false));
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Writes a sample with a single label
+fn write_labeled(out: &mut String, name: &str, label: &str, value: &str, sample: impl std::fmt::Display) {
+    let _ = writeln!(out, "{name}{{{label}=\"{}\"}} {sample}", escape(value, true));
+}
+
+async fn metrics(State(state): State<HandlerState>) -> Response {
+    let (orders, version) = {
+        let orders = state.orders.lock().await;
+        (orders.orders.len(), orders.version)
+    };
+
+    let metrics = &state.metrics;
+    let mut out = String::new();
+
+    write_header(&mut out, "pizzabot_connections", "gauge", "Open WebSocket connections");
+    let _ = writeln!(out, "pizzabot_connections {}", metrics.connections.load(Ordering::Relaxed));
+
+    write_header(&mut out, "pizzabot_messages_total", "counter", "Received client packages by variant");
+    for (variant, count) in metrics.messages.lock().unwrap().iter() {
+        write_labeled(&mut out, "pizzabot_messages_total", "package", variant, count);
+    }
+
+    write_header(&mut out, "pizzabot_rebalance_duration_seconds", "histogram", "Time spent rebalancing all orders after a change");
+    metrics.rebalance.write(&mut out, "pizzabot_rebalance_duration_seconds");
+
+    write_header(&mut out, "pizzabot_lag_events_total", "counter", "Times a connection fell behind the broadcast channel");
+    let _ = writeln!(out, "pizzabot_lag_events_total {}", state.lag_events.load(Ordering::Relaxed));
+
+    write_header(&mut out, "pizzabot_orders", "gauge", "Orders in the current round");
+    let _ = writeln!(out, "pizzabot_orders {orders}");
+
+    write_header(&mut out, "pizzabot_state_version", "gauge", "Version of the order state");
+    let _ = writeln!(out, "pizzabot_state_version {version}");
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response()
+}
+
+/// The process is running and serving requests
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// The server accepts new connections, which stops once it is shutting down
+async fn readyz(State(state): State<HandlerState>) -> Response {
+    if *state.shutdown.borrow() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down").into_response()
+    }
+
+    "ready".into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use crate::state::AppState;
+
+    use super::*;
+
+    #[test]
+    fn escapes_label_values_and_help_texts() {
+        assert_eq!(escape("MakeOrder", true), "MakeOrder");
+        assert_eq!(escape(r#"say "hi""#, true), r#"say \"hi\""#);
+        assert_eq!(escape("C:\\pizza\nline", true), "C:\\\\pizza\\nline");
+        // Quotes only end label values, help texts keep them
+        assert_eq!(escape(r#"the "best" pizza"#, false), r#"the "best" pizza"#);
+        assert_eq!(escape("two\nlines", false), "two\\nlines");
+    }
+
+    #[test]
+    fn writes_escaped_samples_on_a_single_line() {
+        let mut out = String::new();
+        write_header(&mut out, "test_total", "counter", "Line\nbreak");
+        write_labeled(&mut out, "test_total", "name", "a\"b\nc", 3);
+
+        assert_eq!(out, "# HELP test_total Line\\nbreak\n# TYPE test_total counter\ntest_total{name=\"a\\\"b\\nc\"} 3\n");
+    }
+
+    #[test]
+    fn writes_cumulative_histogram_buckets() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(2));
+        histogram.observe(Duration::from_secs(10));
+
+        let mut out = String::new();
+        histogram.write(&mut out, "test");
+        let lines: Vec<_> = out.lines().collect();
+
+        assert_eq!(lines[0], "test_bucket{le=\"0.0001\"} 1");
+        assert_eq!(lines[3], "test_bucket{le=\"0.005\"} 2");
+        assert_eq!(lines[9], "test_bucket{le=\"5\"} 2");
+        assert_eq!(lines[10], "test_bucket{le=\"+Inf\"} 3");
+        assert_eq!(lines[12], "test_count 3");
+    }
+
+    #[tokio::test]
+    async fn serves_the_counted_messages() {
+        let state = Arc::new(AppState::temporary("metrics"));
+        state.metrics.count_message(&ClientPackage::RequestAll);
+        state.metrics.count_message(&ClientPackage::RequestAll);
+
+        let request = Request::builder().uri("/metrics").body(Body::empty()).unwrap();
+        let response = router().with_state(state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+
+        assert!(body.lines().any(|line| line == "pizzabot_messages_total{package=\"RequestAll\"} 2"), "{body}");
+        assert!(body.lines().any(|line| line == "pizzabot_orders 0"), "{body}");
+    }
+}
@@ -0,0 +1,146 @@
+//! Saving and restoring the round across restarts
+
+use std::{borrow::Cow, collections::HashMap, io, path::Path, sync::mpsc};
+
+use pizza_bot_rs_common::{communication::{EditToken, FullOrderData}, orders::OrderState};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::error;
+
+const STATE_FILE: &str = "state.json";
+/// Written on every new order, so the tokens survive a crash even though the orders are only saved on shutdown
+const TOKENS_FILE: &str = "tokens.json";
+
+#[derive(Serialize, Deserialize)]
+struct SavedState<'a> {
+    orders: FullOrderData<'a>,
+    tokens: Cow<'a, HashMap<String, EditToken>>,
+}
+
+/// Restores the state written by [`save`] with the tokens written by the [`TokenWriter`] since, if there is any.
+/// Without a saved state the orders start empty and have to be rebuilt from the event log.
+pub(crate) fn load(data_dir: &Path) -> io::Result<Option<(OrderState, HashMap<String, EditToken>)>> {
+    let saved = read_json::<SavedState>(data_dir, STATE_FILE)?;
+    let tokens = read_json(data_dir, TOKENS_FILE)?;
+
+    Ok(match (saved, tokens) {
+        (Some(saved), tokens) => Some((OrderState::from_full_data(saved.orders), tokens.unwrap_or_else(|| saved.tokens.into_owned()))),
+        (None, Some(tokens)) => Some((OrderState::new(0), tokens)),
+        (None, None) => None,
+    })
+}
+
+/// Writes the state to the data directory, replacing the previous one atomically
+pub(crate) fn save(data_dir: &Path, orders: &OrderState, tokens: &HashMap<String, EditToken>) -> io::Result<()> {
+    let saved = SavedState {
+        orders: orders.to_full_data(),
+        tokens: Cow::Borrowed(tokens),
+    };
+
+    write_json(data_dir, STATE_FILE, &saved)
+}
+
+enum TokenCommand {
+    Save(HashMap<String, EditToken>),
+    /// Answered once every save sent before is written
+    Flush(oneshot::Sender<()>),
+}
+
+/// Writes the tokens, which unlike the orders can not be rebuilt from the event log, on a dedicated thread,
+/// so saving them never blocks a request on the disk
+pub(crate) struct TokenWriter {
+    writer: mpsc::Sender<TokenCommand>,
+}
+
+impl TokenWriter {
+    pub fn start(data_dir: &Path) -> Self {
+        let data_dir = data_dir.to_owned();
+        let (writer, commands) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(String::from("token writer"))
+            .spawn(move || write_tokens(&data_dir, commands))
+            .expect("could not start the token writer thread");
+
+        Self { writer }
+    }
+
+    /// Queues the tokens for writing, failures are only logged so they never reject a request
+    pub fn save(&self, tokens: HashMap<String, EditToken>) {
+        if self.writer.send(TokenCommand::Save(tokens)).is_err() {
+            error!("could not save the edit tokens, the writer stopped");
+        }
+    }
+
+    /// Waits until the tokens saved so far are written
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.writer.send(TokenCommand::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+/// Writes the saved tokens until the [`TokenWriter`] is dropped, skipping those replaced by newer ones in the meantime
+fn write_tokens(data_dir: &Path, commands: mpsc::Receiver<TokenCommand>) {
+    let mut pending = None;
+
+    loop {
+        // Only blocks once everything queued so far is handled
+        let command = match pending {
+            Some(_) => commands.try_recv().ok(),
+            None => match commands.recv() {
+                Ok(command) => Some(command),
+                Err(_) => return,
+            },
+        };
+
+        match command {
+            Some(TokenCommand::Save(tokens)) => pending = Some(tokens),
+            Some(TokenCommand::Flush(done)) => {
+                if let Some(tokens) = pending.take() {
+                    write_tokens_file(data_dir, &tokens);
+                }
+                let _ = done.send(());
+            },
+            None => {
+                if let Some(tokens) = pending.take() {
+                    write_tokens_file(data_dir, &tokens);
+                }
+            },
+        }
+    }
+}
+
+fn write_tokens_file(data_dir: &Path, tokens: &HashMap<String, EditToken>) {
+    if let Err(err) = write_json(data_dir, TOKENS_FILE, tokens) {
+        error!("could not save the edit tokens to {}: {err}", data_dir.display());
+    }
+}
+
+/// Reads a file written by [`write_json`], `None` if there is none
+pub(crate) fn read_json<T: DeserializeOwned>(data_dir: &Path, file: &str) -> io::Result<Option<T>> {
+    let content = match std::fs::read_to_string(data_dir.join(file)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    Ok(Some(serde_json::from_str(&content)?))
+}
+
+/// Replaces the file in the data directory atomically, so a crash never leaves a partially written one
+pub(crate) fn write_json(data_dir: &Path, file: &str, value: &impl Serialize) -> io::Result<()> {
+    let temporary = data_dir.join(format!("{file}.tmp"));
+    std::fs::write(&temporary, serde_json::to_vec(value)?)?;
+    std::fs::rename(temporary, data_dir.join(file))
+}
+
+/// An empty data directory for a single test, left behind for inspection
+#[cfg(test)]
+pub(crate) fn temporary_data_dir(test: &str) -> std::path::PathBuf {
+    let data_dir = std::env::temp_dir().join(format!("pizzabot-{test}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    std::fs::create_dir_all(&data_dir).expect("Could not create temporary data directory");
+
+    data_dir
+}
@@ -0,0 +1,136 @@
+//! Tracks which orders currently have an open connection
+
+use std::{collections::{HashMap, HashSet}, sync::Mutex};
+
+use crate::state::HandlerState;
+
+/// Number of open connections per order name
+#[derive(Default)]
+pub(crate) struct Presence {
+    connections: Mutex<HashMap<String, usize>>,
+}
+
+impl Presence {
+    /// Returns whether the name was not present before
+    fn join(&self, name: &str) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(name.to_owned()).or_insert(0);
+        *count += 1;
+
+        *count == 1
+    }
+
+    /// Returns whether any name is no longer present
+    fn leave<'a>(&self, names: impl IntoIterator<Item = &'a String>) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+        let mut changed = false;
+        for name in names {
+            let Some(count) = connections.get_mut(name) else {
+                continue
+            };
+
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(name);
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.connections.lock().unwrap().keys().cloned().collect();
+        names.sort_unstable();
+
+        names
+    }
+}
+
+/// The names a single connection identified as, released once it is dropped
+pub(crate) struct ConnectionPresence {
+    state: HandlerState,
+    names: HashSet<String>,
+}
+
+impl ConnectionPresence {
+    pub fn new(state: HandlerState) -> Self {
+        Self {
+            state,
+            names: HashSet::new(),
+        }
+    }
+
+    pub fn join(&mut self, name: String) {
+        if self.names.contains(&name) {
+            return
+        }
+
+        if self.state.presence.join(&name) {
+            self.state.broadcast_presence();
+        }
+        self.names.insert(name);
+    }
+}
+
+impl Drop for ConnectionPresence {
+    fn drop(&mut self) {
+        if self.state.presence.leave(&self.names) {
+            self.state.broadcast_presence();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::broadcast;
+
+    use crate::state::{AppState, EncodedPackage};
+
+    use super::*;
+
+    /// Number of presence packages broadcast since the last call
+    fn broadcasts(rx: &mut broadcast::Receiver<Arc<EncodedPackage>>) -> usize {
+        std::iter::from_fn(|| rx.try_recv().ok()).count()
+    }
+
+    #[test]
+    fn counts_every_connection_of_a_name() {
+        let state = Arc::new(AppState::temporary("presence-count"));
+        let mut rx = state.broadcast.subscribe();
+
+        let mut laptop = ConnectionPresence::new(state.clone());
+        let mut phone = ConnectionPresence::new(state.clone());
+        laptop.join(String::from("alice"));
+        assert_eq!(broadcasts(&mut rx), 1);
+        phone.join(String::from("alice"));
+        assert_eq!(broadcasts(&mut rx), 0);
+
+        // alice stays present until the last of the connections closes
+        drop(laptop);
+        assert_eq!(state.presence.names(), ["alice"]);
+        assert_eq!(broadcasts(&mut rx), 0);
+        drop(phone);
+        assert!(state.presence.names().is_empty());
+        assert_eq!(broadcasts(&mut rx), 1);
+    }
+
+    #[test]
+    fn counts_a_repeated_join_of_one_connection_once() {
+        let state = Arc::new(AppState::temporary("presence-repeated"));
+        let mut rx = state.broadcast.subscribe();
+
+        let mut connection = ConnectionPresence::new(state.clone());
+        connection.join(String::from("alice"));
+        connection.join(String::from("alice"));
+        connection.join(String::from("bob"));
+        assert_eq!(state.presence.names(), ["alice", "bob"]);
+        assert_eq!(broadcasts(&mut rx), 2);
+
+        drop(connection);
+        assert!(state.presence.names().is_empty());
+        assert_eq!(broadcasts(&mut rx), 1);
+    }
+}
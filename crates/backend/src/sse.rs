@@ -0,0 +1,69 @@
+//! Read-only Server-Sent Events stream of the broadcast packages, for dashboards that do not speak the WebSocket protocol
+
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+    extract::State, http::HeaderMap, response::sse::{Event, KeepAlive, Sse}, routing::get, Router
+};
+use futures::{stream, Stream, StreamExt};
+use pizza_bot_rs_common::{communication::ServerPackage, orders::OrderStateVersion};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::state::{EncodedPackage, HandlerState};
+
+pub(crate) fn router() -> Router<HandlerState> {
+    Router::new()
+        .route("/sse", get(stream_packages))
+}
+
+/// Every event carries a [`ServerPackage`] as JSON, those that change the orders have their version as ID
+fn to_event(package: &EncodedPackage) -> Event {
+    let event = Event::default().data(package.json());
+
+    match package.version() {
+        Some(version) => event.id(version.to_string()),
+        None => event,
+    }
+}
+
+/// Starts with the packages of [`AppState::resume_packages`](crate::state::AppState::resume_packages) for `Last-Event-ID`
+async fn stream_packages(State(state): State<HandlerState>, headers: HeaderMap) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let last_version = headers.get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse::<OrderStateVersion>().ok());
+
+    let (initial, rx) = {
+        let (orders, rx) = state.subscribe_with_snapshot().await;
+
+        let initial = state.resume_packages(&orders, last_version);
+
+        (initial, rx)
+    };
+    let presence = Arc::new(EncodedPackage::new(&ServerPackage::Presence(state.presence.names())));
+
+    let shutdown = state.shutdown.subscribe();
+
+    let broadcast = stream::unfold((rx, shutdown, state), |(mut rx, mut shutdown, state)| async move {
+        let msg = tokio::select! {
+            biased;
+            // Ends the response, so the graceful shutdown does not wait for the stream
+            _ = shutdown.wait_for(|&shutdown| shutdown) => return None,
+            msg = rx.recv() => msg,
+        };
+
+        let packages = match msg {
+            Ok(package) => vec![package],
+            Err(RecvError::Lagged(skipped)) => state.resync(&mut rx, skipped, "event stream").await.to_vec(),
+            Err(RecvError::Closed) => return None,
+        };
+
+        Some((stream::iter(packages), (rx, shutdown, state)))
+    });
+
+    let events = stream::iter(initial)
+        .chain(stream::once(async { presence }))
+        .chain(broadcast.flatten())
+        .map(|package| Ok(to_event(&package)));
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,1059 @@
+use std::{borrow::Cow, collections::{HashMap, VecDeque}, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Instant};
+
+use axum::extract::ws::Message;
+use pizza_bot_rs_common::{audit::{AuditChange, AuditEvent}, communication::{Capability, DeleteOrderResponse, DistributionChange, EditOrderResponse, EditToken, GetOrderResponse, IdentifyResponse, MakeOrderResponse, OrderChange, QueryEventsResponse, Response, RevertOrderResponse, RollbackResponse, ServerPackage, SetAnnouncementResponse}, export::{self, ExportFormat}, orders::{FullOrder, Order, OrderAmount, OrderInfo, OrderRequest, OrderState, OrderStateVersion, PizzaAmount, PizzaKindArray, Price}, encoding::Encoding, validation};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::sync::{broadcast, watch, Mutex, MutexGuard};
+use tracing::{info, warn};
+
+use crate::{audit::{self, AuditLog, Origin}, balancing, config::Config, error::ProtocolError, idempotency::{IdempotencyCache, RequestKey}, limits::IpLimits, metrics::Metrics, persistence::{self, TokenWriter}, presence::Presence};
+
+pub(crate) trait OrderStateExt {
+    fn try_add_order(&mut self, name: String, order: Order, config: &Config) -> Option<(FullOrder, Vec<DistributionChange>)>;
+    fn try_edit_order(&mut self, name: String, order: Order, config: &Config) -> Option<(FullOrder, Vec<DistributionChange>)>;
+    fn try_delete_order(&mut self, name: &str, config: &Config) -> Option<(OrderInfo, Vec<DistributionChange>)>;
+    fn get_order(&self, name: &str) -> Option<FullOrder>;
+    /// Rebalances all orders, returning every distribution that changed
+    fn finalize_update(&mut self, config: &Config) -> Vec<DistributionChange>;
+}
+
+impl OrderStateExt for OrderState {
+    fn try_add_order(&mut self, name: String, order: Order, config: &Config) -> Option<(FullOrder, Vec<DistributionChange>)> {
+        match self.order_infos.binary_search_by(|info| info.name.cmp(&name)) {
+            Ok(_) => None,
+            Err(index) => {
+                let order = Order {
+                    preference: order.preference.clamp(0.0, 1.0),
+                    ..order
+                };
+                self.order_infos.insert(index, OrderInfo {
+                    name,
+                    has_paid: false,
+                    price: Price { cents: 0 },
+                    // The version `finalize_update` is about to set
+                    version: self.version + 1,
+                });
+                self.orders.insert(index, order);
+                // Keeps the distributions aligned with the orders, so `finalize_update` can compare them
+                self.distributions.insert(index, PizzaKindArray::splat(0));
+
+                let changes = self.finalize_update(config);
+
+                Some((FullOrder {
+                    info: self.order_infos[index].clone(),
+                    order,
+                    distribution: self.distributions[index]
+                }, changes))
+            },
+        }
+    }
+
+    fn try_edit_order(&mut self, name: String, order: Order, config: &Config) -> Option<(FullOrder, Vec<DistributionChange>)> {
+        match self.order_infos.binary_search_by(|info| info.name.cmp(&name)) {
+            Ok(index) => {
+                let order = Order {
+                    preference: order.preference.clamp(0.0, 1.0),
+                    ..order
+                };
+                self.order_infos[index] = OrderInfo {
+                    name,
+                    has_paid: false,
+                    price: Price { cents: 0 },
+                    // The version `finalize_update` is about to set
+                    version: self.version + 1,
+                };
+                self.orders[index] = order;
+
+                let changes = self.finalize_update(config);
+
+                Some((FullOrder {
+                    info: self.order_infos[index].clone(),
+                    order,
+                    distribution: self.distributions[index]
+                }, changes))
+            },
+            Err(_) => None
+        }
+    }
+
+    fn try_delete_order(&mut self, name: &str, config: &Config) -> Option<(OrderInfo, Vec<DistributionChange>)> {
+        match self.order_infos.binary_search_by(|info| info.name.as_str().cmp(name)) {
+            Ok(index) => {
+                let info = self.order_infos.remove(index);
+                self.orders.remove(index);
+                self.distributions.remove(index);
+
+                let changes = self.finalize_update(config);
+
+                Some((info, changes))
+            },
+            Err(_) => None
+        }
+    }
+
+    fn get_order(&self, name: &str) -> Option<FullOrder> {
+        let index = self.order_infos.binary_search_by(|info| info.name.as_str().cmp(name)).ok()?;
+
+        Some(FullOrder {
+            info: self.order_infos[index].clone(),
+            order: self.orders[index],
+            distribution: self.distributions[index]
+        })
+    }
+
+    fn finalize_update(&mut self, config: &Config) -> Vec<DistributionChange> {
+        let (_, pizzas, distributions, valid) = balancing::get_best(config.pizza.pieces_per_pizza as OrderAmount, &config.balancing, &self.orders);
+
+        let changes = distributions.iter()
+            .enumerate()
+            .filter(|&(index, distribution)| self.distributions.get(index) != Some(distribution))
+            .map(|(index, &distribution)| DistributionChange {
+                name: self.order_infos[index].name.clone(),
+                distribution,
+            })
+            .collect();
+
+        self.config = pizzas;
+        self.distributions = distributions;
+        self.distributions_valid = valid;
+
+        self.version += 1;
+
+        changes
+    }
+}
+
+const EDIT_TOKEN_LENGTH: usize = 32;
+/// Number of previous values kept per order for [`AppState::revert_order`]
+const ORDER_HISTORY_LENGTH: usize = 16;
+
+pub(crate) fn generate_token() -> EditToken {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(EDIT_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Drops the tokens of names that were used for a new order after `version`,
+/// since they belong to that order rather than to the one of the same name at `version`, which is left to the organizer
+pub(crate) fn forget_reused_tokens(tokens: &mut HashMap<String, EditToken>, events: &[AuditEvent], version: OrderStateVersion) {
+    for event in events.iter().filter(|event| event.version > version) {
+        if let AuditChange::AddOrder(request) = &event.change {
+            tokens.remove(&request.name);
+        }
+    }
+}
+
+/// Serializes `package` into a WebSocket message of the given encoding
+pub(crate) fn encode_message(package: &impl serde::ser::Serialize, encoding: Encoding) -> Message {
+    let Ok(bytes) = encoding.encode(package) else {
+        // TODO handle, although currently the serializer should not be able to fail
+        panic!("Could not create response");
+    };
+
+    if encoding.is_binary() {
+        Message::Binary(bytes)
+    } else {
+        Message::Text(String::from_utf8(bytes).expect("JSON is always valid UTF-8"))
+    }
+}
+
+/// What a broadcast package changed about the round, for consumers like the chat bot that do not read the encoded packages
+pub(crate) enum RoundChange {
+    /// See [`ServerPackage::Update`]
+    Update {
+        change: OrderChange,
+        config: PizzaKindArray<PizzaAmount>,
+        distributions: Vec<DistributionChange>,
+        distributions_valid: bool,
+    },
+    /// All orders were replaced, the new version of a [`ServerPackage::All`]
+    Reset(OrderStateVersion),
+    Announcement(String),
+}
+
+impl RoundChange {
+    fn of(package: &ServerPackage) -> Option<Self> {
+        match package {
+            ServerPackage::Update { change, config, distributions, distributions_valid, .. } => Some(RoundChange::Update {
+                change: change.clone(),
+                config: *config,
+                distributions: distributions.clone(),
+                distributions_valid: *distributions_valid,
+            }),
+            ServerPackage::All(all) => Some(RoundChange::Reset(all.version)),
+            ServerPackage::Announcement(announcement) => Some(RoundChange::Announcement(announcement.to_string())),
+            ServerPackage::Hello(_) |
+            ServerPackage::Response { .. } |
+            ServerPackage::Presence(_) |
+            ServerPackage::Error(_) => None,
+        }
+    }
+}
+
+/// A broadcast package, serialized once per encoding instead of once per connection
+pub(crate) struct EncodedPackage {
+    json: Message,
+    message_pack: Message,
+    version: Option<OrderStateVersion>,
+    required_capability: Option<Capability>,
+    change: Option<RoundChange>,
+}
+
+impl EncodedPackage {
+    pub fn new(package: &ServerPackage) -> Self {
+        Self {
+            json: encode_message(package, Encoding::Json),
+            message_pack: encode_message(package, Encoding::MessagePack),
+            version: package.version(),
+            required_capability: package.required_capability(),
+            change: RoundChange::of(package),
+        }
+    }
+
+    /// What the package changed, `None` for packages that leave the round as it is
+    pub fn change(&self) -> Option<&RoundChange> {
+        self.change.as_ref()
+    }
+
+    /// See [`ServerPackage::version`]
+    pub fn version(&self) -> Option<OrderStateVersion> {
+        self.version
+    }
+
+    /// See [`ServerPackage::required_capability`]
+    pub fn required_capability(&self) -> Option<Capability> {
+        self.required_capability
+    }
+
+    pub fn json(&self) -> &str {
+        match &self.json {
+            Message::Text(text) => text,
+            _ => unreachable!("JSON is always encoded as text"),
+        }
+    }
+
+    pub fn message(&self, encoding: Encoding) -> Message {
+        match encoding {
+            Encoding::Json => self.json.clone(),
+            Encoding::MessagePack => self.message_pack.clone(),
+        }
+    }
+}
+
+pub(crate) fn broadcast_serialized(package: ServerPackage, sender: &broadcast::Sender<Arc<EncodedPackage>>) {
+    // Only fails if no client is currently subscribed, in which case there is nobody to notify
+    let _ = sender.send(Arc::new(EncodedPackage::new(&package)));
+}
+
+pub(crate) struct AppState {
+    pub config: Config,
+    pub orders: Mutex<OrderState>,
+    /// Edit token of every order by name, always lock after `orders`
+    pub tokens: Mutex<HashMap<String, EditToken>>,
+    token_writer: TokenWriter,
+    /// Previous values of every order by name, newest last, always lock after `tokens`
+    history: Mutex<HashMap<String, VecDeque<Order>>>,
+    pub organizer_token: EditToken,
+    pub broadcast: broadcast::Sender<Arc<EncodedPackage>>,
+    /// The last broadcast updates, oldest first, only modified while holding `orders`
+    recent_updates: std::sync::Mutex<VecDeque<Arc<EncodedPackage>>>,
+    /// Set once the server shuts down, every connection holds a receiver until it is closed
+    pub shutdown: watch::Sender<bool>,
+    /// Number of times a connection fell behind the broadcast channel
+    pub lag_events: AtomicUsize,
+    pub presence: Presence,
+    /// Mutating HTTP requests per IP address, WebSocket connections limit their messages on their own
+    pub message_limits: IpLimits,
+    /// New orders per IP address, only charged for orders that were actually made
+    pub order_limits: IpLimits,
+    pub metrics: Metrics,
+    pub audit: AuditLog,
+    /// Responses of recent mutating requests, always lock before `orders`
+    pub idempotency: IdempotencyCache,
+}
+
+impl AppState {
+    pub fn new(config: Config, orders: OrderState, tokens: HashMap<String, EditToken>, broadcast: broadcast::Sender<Arc<EncodedPackage>>, organizer_token: EditToken, audit: AuditLog) -> Self {
+        let message_limits = IpLimits::new(config.limits.message_burst, config.limits.messages_per_minute as f64 / 60.0);
+        let order_limits = IpLimits::new(config.limits.orders_per_ip_per_hour, config.limits.orders_per_ip_per_hour as f64 / (60.0 * 60.0));
+        let token_writer = TokenWriter::start(&config.data_dir);
+
+        Self {
+            config,
+            orders: Mutex::new(orders),
+            token_writer,
+            tokens: Mutex::new(tokens),
+            history: Mutex::new(HashMap::new()),
+            organizer_token,
+            broadcast,
+            recent_updates: std::sync::Mutex::new(VecDeque::new()),
+            shutdown: watch::Sender::new(false),
+            lag_events: AtomicUsize::new(0),
+            presence: Presence::default(),
+            message_limits,
+            order_limits,
+            metrics: Metrics::default(),
+            audit,
+            idempotency: IdempotencyCache::default(),
+        }
+    }
+
+    /// Persists the current round to the data directory
+    pub async fn save(&self) -> std::io::Result<()> {
+        let orders = self.orders.lock().await;
+        let tokens = self.tokens.lock().await;
+
+        persistence::save(&self.config.data_dir, &orders, &tokens)?;
+        self.token_writer.save(tokens.clone());
+        drop(tokens);
+        drop(orders);
+
+        self.token_writer.flush().await;
+        Ok(())
+    }
+
+    /// Whether `token` is the organizer token, which a missing token never is
+    fn is_organizer(&self, token: Option<&str>) -> bool {
+        token == Some(self.organizer_token.as_str())
+    }
+
+    /// Whether `token` allows modifying the order of `name`, orders without a stored token are left to the organizer
+    fn is_authorized(&self, tokens: &HashMap<String, EditToken>, name: &str, token: Option<&str>) -> bool {
+        self.is_organizer(token) || tokens.get(name).is_some_and(|expected| Some(expected.as_str()) == token)
+    }
+
+    fn broadcast_change(&self, change: OrderChange, distributions: Vec<DistributionChange>, orders: &OrderState) {
+        let package = Arc::new(EncodedPackage::new(&ServerPackage::Update {
+            change,
+            config: orders.config,
+
+            version: orders.version,
+            distributions,
+            distributions_valid: orders.distributions_valid,
+        }));
+
+        let mut recent = self.recent_updates.lock().unwrap();
+        if recent.len() >= self.config.resume_capacity {
+            recent.pop_front();
+        }
+        if self.config.resume_capacity > 0 {
+            recent.push_back(package.clone());
+        }
+        drop(recent);
+
+        // Only fails if no client is currently subscribed, in which case there is nobody to notify
+        let _ = self.broadcast.send(package);
+    }
+
+    /// The updates that lead from `version` to `current`, `None` if some of them are no longer kept.
+    /// Call while holding `orders`, so no update is broadcast meanwhile.
+    pub fn updates_since(&self, version: OrderStateVersion, current: OrderStateVersion) -> Option<Vec<Arc<EncodedPackage>>> {
+        if version > current {
+            return None
+        }
+
+        let updates: Vec<_> = self.recent_updates.lock().unwrap()
+            .iter()
+            .filter(|update| update.version().is_some_and(|update| update > version))
+            .cloned()
+            .collect();
+
+        // Each update increments the version by one, anything else like a rollback leaves a gap
+        if updates.len() != current - version {
+            return None
+        }
+
+        Some(updates)
+    }
+
+    /// The packages a stream that last saw `last_version` starts with.
+    /// These are the missed updates and the current announcement if `last_version` is recent, otherwise the full state.
+    pub fn resume_packages(&self, orders: &OrderState, last_version: Option<OrderStateVersion>) -> Vec<Arc<EncodedPackage>> {
+        match last_version.and_then(|version| self.updates_since(version, orders.version)) {
+            Some(mut updates) => {
+                info!("event stream resumed after version {} with {} updates", orders.version - updates.len(), updates.len());
+                // Announcements are not kept with the updates, so the current one is resent in case it changed meanwhile
+                updates.push(Arc::new(EncodedPackage::new(&ServerPackage::Announcement(Cow::Borrowed(&orders.announcement)))));
+                updates
+            },
+            None => vec![Arc::new(EncodedPackage::new(&ServerPackage::All(orders.to_full_data())))],
+        }
+    }
+
+    /// Locks the orders and subscribes to the broadcast.
+    /// Updates are only broadcast while holding the orders, so the receiver continues right after the locked snapshot.
+    pub async fn subscribe_with_snapshot(&self) -> (MutexGuard<'_, OrderState>, broadcast::Receiver<Arc<EncodedPackage>>) {
+        let orders = self.orders.lock().await;
+        let rx = self.broadcast.subscribe();
+
+        (orders, rx)
+    }
+
+    /// Resubscribes a receiver that lagged behind and returns the full state and presence it continues from
+    pub async fn resync(&self, rx: &mut broadcast::Receiver<Arc<EncodedPackage>>, skipped: u64, who: impl std::fmt::Display) -> [Arc<EncodedPackage>; 2] {
+        let events = self.lag_events.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("{who} lagged behind by {skipped} updates, resending full state ({events} lag events so far)");
+
+        let (orders, resubscribed) = self.subscribe_with_snapshot().await;
+        *rx = resubscribed;
+        let all = Arc::new(EncodedPackage::new(&ServerPackage::All(orders.to_full_data())));
+        drop(orders);
+
+        // Presence changes might have been skipped as well
+        [all, Arc::new(EncodedPackage::new(&ServerPackage::Presence(self.presence.names())))]
+    }
+
+    pub fn broadcast_presence(&self) {
+        broadcast_serialized(ServerPackage::Presence(self.presence.names()), &self.broadcast);
+    }
+
+    pub async fn make_order(&self, request: OrderRequest, origin: &Origin) -> MakeOrderResponse {
+        let request = match validation::validate_request(request) {
+            Ok(request) => request,
+            Err(err) => {
+                info!("Order rejected: {err}");
+                return MakeOrderResponse::Invalid(err)
+            }
+        };
+        info!("`{}` made request `(amount: {:?}, preference: {})`", request.name, request.order.amounts.0, request.order.preference);
+
+        let mut orders = self.orders.lock().await;
+        if orders.orders.len() >= self.config.limits.max_orders {
+            return MakeOrderResponse::TooManyOrders
+        }
+
+        let started = Instant::now();
+        let Some((full, distributions)) = orders.try_add_order(request.name, request.order, &self.config) else {
+            return MakeOrderResponse::NameAlreadyRegistered
+        };
+        self.metrics.rebalance.observe(started.elapsed());
+
+        let token = generate_token();
+        let mut tokens = self.tokens.lock().await;
+        tokens.insert(full.info.name.clone(), token.clone());
+        // Written in the background, a crash right after can lose the token, leaving the order to the organizer
+        self.token_writer.save(tokens.clone());
+        drop(tokens);
+
+        self.audit.record(origin, orders.version, false, AuditChange::AddOrder(OrderRequest { name: full.info.name.clone(), order: full.order }));
+        self.broadcast_change(OrderChange::Set(full), distributions, &orders);
+
+        MakeOrderResponse::Success(token)
+    }
+
+    /// Makes the order like [`make_order`](Self::make_order), unless `key` was seen before, see [`IdempotencyCache::try_run`].
+    /// Only orders that are actually made count against the limit of new orders of the address of `origin`.
+    pub async fn make_order_limited(&self, request: OrderRequest, key: Option<RequestKey>, origin: &Origin) -> Result<Response, ProtocolError> {
+        let ip = origin.address.ip();
+
+        self.idempotency.try_run(key, async {
+            if !self.order_limits.try_take(ip) {
+                let err = ProtocolError::OrderRateLimited;
+                info!("{} was rate limited: {err}", origin.address);
+                return Err(err)
+            }
+
+            let response = self.make_order(request, origin).await;
+            if !matches!(response, MakeOrderResponse::Success(_)) {
+                self.order_limits.refund(ip);
+            }
+
+            Ok(Response::MakeOrder(response))
+        }).await
+    }
+
+    /// Rejects the edit if `expected_version` is given and the order changed since
+    pub async fn edit_order(&self, request: OrderRequest, token: Option<&str>, expected_version: Option<OrderStateVersion>, origin: &Origin) -> EditOrderResponse {
+        let request = match validation::validate_request(request) {
+            Ok(request) => request,
+            Err(err) => {
+                info!("Order edit rejected: {err}");
+                return EditOrderResponse::Invalid(err)
+            }
+        };
+        info!("Order edit for `{}` with `(amount: {:?}, preference: {})` requested", request.name, request.order.amounts.0, request.order.preference);
+
+        let mut orders = self.orders.lock().await;
+        let Some(previous) = orders.get_order(&request.name) else {
+            return EditOrderResponse::NameNotFound
+        };
+
+        let tokens = self.tokens.lock().await;
+        if !self.is_authorized(&tokens, &request.name, token) {
+            return EditOrderResponse::InvalidToken
+        }
+        drop(tokens);
+
+        if expected_version.is_some_and(|expected| expected != previous.info.version) {
+            info!("Order edit for `{}` rejected, it changed in version {}", request.name, previous.info.version);
+            return EditOrderResponse::Conflict(previous)
+        }
+
+        let started = Instant::now();
+        let Some((full, distributions)) = orders.try_edit_order(request.name, request.order, &self.config) else {
+            return EditOrderResponse::NameNotFound
+        };
+        self.metrics.rebalance.observe(started.elapsed());
+
+        let mut history = self.history.lock().await;
+        let previous_orders = history.entry(full.info.name.clone()).or_default();
+        if previous_orders.len() == ORDER_HISTORY_LENGTH {
+            previous_orders.pop_front();
+        }
+        previous_orders.push_back(previous.order);
+        drop(history);
+
+        self.audit.record(origin, orders.version, self.is_organizer(token), AuditChange::EditOrder(OrderRequest { name: full.info.name.clone(), order: full.order }));
+        self.broadcast_change(OrderChange::Set(full), distributions, &orders);
+
+        EditOrderResponse::Success
+    }
+
+    pub async fn delete_order(&self, name: &str, token: Option<&str>, origin: &Origin) -> DeleteOrderResponse {
+        // Invalid names can not belong to any order
+        let Ok(name) = validation::normalize_name(name) else {
+            return DeleteOrderResponse::NameNotFound
+        };
+        let name = name.as_str();
+        info!("Order deletion for `{name}` requested");
+
+        let mut orders = self.orders.lock().await;
+        if orders.get_order(name).is_none() {
+            return DeleteOrderResponse::NameNotFound
+        }
+
+        let tokens = self.tokens.lock().await;
+        if !self.is_authorized(&tokens, name, token) {
+            return DeleteOrderResponse::InvalidToken
+        }
+
+        let started = Instant::now();
+        let Some((info, distributions)) = orders.try_delete_order(name, &self.config) else {
+            return DeleteOrderResponse::NameNotFound
+        };
+        self.metrics.rebalance.observe(started.elapsed());
+        // The token is kept, so a rollback to before the deletion gives the order back to its owner
+        drop(tokens);
+        self.history.lock().await.remove(&info.name);
+
+        self.audit.record(origin, orders.version, self.is_organizer(token), AuditChange::DeleteOrder(info.name.clone()));
+        self.broadcast_change(OrderChange::Remove(info.name), distributions, &orders);
+
+        DeleteOrderResponse::Success
+    }
+
+    pub async fn revert_order(&self, name: &str, token: Option<&str>, origin: &Origin) -> RevertOrderResponse {
+        let Ok(name) = validation::normalize_name(name) else {
+            return RevertOrderResponse::NameNotFound
+        };
+        info!("Revert of the last edit of `{name}` requested");
+
+        let mut orders = self.orders.lock().await;
+        // Checked before taking from the history, so the edit below can not fail
+        if orders.get_order(&name).is_none() {
+            return RevertOrderResponse::NameNotFound
+        }
+
+        let tokens = self.tokens.lock().await;
+        if !self.is_authorized(&tokens, &name, token) {
+            return RevertOrderResponse::InvalidToken
+        }
+        drop(tokens);
+
+        let mut history = self.history.lock().await;
+        let Some(previous) = history.get_mut(&name).and_then(VecDeque::pop_back) else {
+            return RevertOrderResponse::NothingToRevert
+        };
+        drop(history);
+
+        let started = Instant::now();
+        let Some((full, distributions)) = orders.try_edit_order(name, previous, &self.config) else {
+            return RevertOrderResponse::NameNotFound
+        };
+        self.metrics.rebalance.observe(started.elapsed());
+
+        self.audit.record(origin, orders.version, self.is_organizer(token), AuditChange::RevertOrder(OrderRequest { name: full.info.name.clone(), order: full.order }));
+        let order = full.order;
+        self.broadcast_change(OrderChange::Set(full), distributions, &orders);
+
+        RevertOrderResponse::Success(order)
+    }
+
+    pub async fn get_order(&self, name: &str) -> GetOrderResponse {
+        let Ok(name) = validation::normalize_name(name) else {
+            return GetOrderResponse::NameNotFound
+        };
+        let name = name.as_str();
+        info!("Order for `{name}` requested");
+
+        match self.orders.lock().await.get_order(name) {
+            Some(full) => GetOrderResponse::Success(full),
+            None => GetOrderResponse::NameNotFound,
+        }
+    }
+
+    pub async fn export(&self, format: ExportFormat) -> String {
+        info!("Export as {format:?} requested");
+
+        let orders = self.orders.lock().await;
+        export::export(&orders, &self.config.pizza, format)
+    }
+
+    /// Checks whether `token` belongs to the order itself, the organizer token does not count
+    pub async fn identify(&self, name: &str, token: &str) -> IdentifyResponse {
+        let Ok(name) = validation::normalize_name(name) else {
+            return IdentifyResponse::NameNotFound
+        };
+        let name = name.as_str();
+
+        let orders = self.orders.lock().await;
+        if orders.get_order(name).is_none() {
+            return IdentifyResponse::NameNotFound
+        }
+
+        let tokens = self.tokens.lock().await;
+        if tokens.get(name).map(String::as_str) != Some(token) {
+            return IdentifyResponse::InvalidToken
+        }
+        drop(tokens);
+
+        info!("Connection identified as `{name}`");
+        IdentifyResponse::Success
+    }
+
+    /// All logged events, optionally only those of a single order
+    pub async fn query_events(&self, name: Option<&str>, token: Option<&str>) -> QueryEventsResponse {
+        if !self.is_organizer(token) {
+            info!("Event log query rejected due to invalid organizer token");
+            return QueryEventsResponse::InvalidToken
+        }
+
+        let mut events = match self.audit.events().await {
+            Ok(events) => events,
+            Err(err) => {
+                tracing::error!("could not read the event log: {err}");
+                return QueryEventsResponse::Unavailable
+            }
+        };
+
+        if let Some(name) = name {
+            let name = validation::normalize_name(name).unwrap_or_else(|_| name.to_owned());
+            events.retain(|event| event.change.order_name() == Some(&name));
+        }
+
+        QueryEventsResponse::Success(events)
+    }
+
+    /// Replaces all orders with their state at `version`, rebuilt from the event log
+    ///
+    /// Restored orders keep their edit token, unless their name was used for a new order since `version`.
+    /// The history of every order is cleared, since its earlier values may not apply to the restored orders.
+    pub async fn rollback(&self, version: OrderStateVersion, token: Option<&str>, origin: &Origin) -> RollbackResponse {
+        if !self.is_organizer(token) {
+            info!("Rollback to version {version} rejected due to invalid organizer token");
+            return RollbackResponse::InvalidToken
+        }
+
+        info!("Rollback to version {version} requested");
+
+        let mut orders = self.orders.lock().await;
+        if version > orders.version {
+            return RollbackResponse::VersionNotFound
+        }
+
+        let events = match self.audit.events().await {
+            Ok(events) => events,
+            Err(err) => {
+                tracing::error!("could not read the event log: {err}");
+                return RollbackResponse::Unavailable
+            }
+        };
+
+        // Not observed as a rebalance, since replaying rebalances once per event and reads the log
+        let mut restored = match audit::replay(&events, version, &self.config) {
+            Ok(restored) => restored,
+            Err(err @ audit::ReplayError::VersionNotReached(_)) => {
+                info!("Rollback to version {version} failed: {err}");
+                return RollbackResponse::VersionNotFound
+            },
+            Err(err @ audit::ReplayError::Diverged(_)) => {
+                tracing::error!("Rollback to version {version} failed: {err}");
+                return RollbackResponse::Unavailable
+            },
+        };
+
+        // Clients only ever see increasing versions
+        restored.version = orders.version + 1;
+        restored.announcement = std::mem::take(&mut orders.announcement);
+        *orders = restored;
+
+        let mut tokens = self.tokens.lock().await;
+        forget_reused_tokens(&mut tokens, &events, version);
+        self.token_writer.save(tokens.clone());
+        drop(tokens);
+        self.history.lock().await.clear();
+        self.idempotency.clear();
+
+        self.audit.record(origin, orders.version, true, AuditChange::Rollback(version));
+        broadcast_serialized(ServerPackage::All(orders.to_full_data()), &self.broadcast);
+
+        RollbackResponse::Success
+    }
+
+    pub async fn set_announcement(&self, announcement: String, token: Option<&str>, origin: &Origin) -> SetAnnouncementResponse {
+        if !self.is_organizer(token) {
+            info!("Announcement `{announcement}` rejected due to invalid organizer token");
+            return SetAnnouncementResponse::InvalidToken
+        }
+
+        info!("Announcement `{announcement}` set");
+
+        let mut orders = self.orders.lock().await;
+        orders.announcement = announcement;
+
+        self.audit.record(origin, orders.version, true, AuditChange::SetAnnouncement(orders.announcement.clone()));
+        broadcast_serialized(ServerPackage::Announcement(Cow::Borrowed(&orders.announcement)), &self.broadcast);
+
+        SetAnnouncementResponse::Success
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    pub const TEST_ORGANIZER_TOKEN: &'static str = "organizer";
+
+    /// A state without any orders, saving to a [`persistence::temporary_data_dir`]
+    pub fn temporary(test: &str) -> Self {
+        Self::temporary_with(test, |_| {})
+    }
+
+    /// Like [`temporary`](Self::temporary), with the default config changed by `configure`
+    pub fn temporary_with(test: &str, configure: impl FnOnce(&mut Config)) -> Self {
+        let mut config = Config {
+            data_dir: persistence::temporary_data_dir(test),
+            ..Config::default()
+        };
+        configure(&mut config);
+        let audit = AuditLog::open(&config.data_dir).unwrap();
+        let (broadcast, _) = broadcast::channel(config.broadcast_capacity);
+
+        Self::new(config, OrderState::new(0), HashMap::new(), broadcast, String::from(Self::TEST_ORGANIZER_TOKEN), audit)
+    }
+}
+
+pub(crate) type HandlerState = Arc<AppState>;
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use pizza_bot_rs_common::orders::PizzaKindArray;
+
+    use super::*;
+
+    fn order(amounts: [usize; 3], preference: f32) -> Order {
+        Order { amounts: PizzaKindArray(amounts), preference }
+    }
+
+    /// Applies an update by name, like the clients do
+    fn apply(state: &mut OrderState, change: OrderChange, distributions: Vec<DistributionChange>) {
+        match change {
+            OrderChange::Set(full) => match state.order_infos.binary_search_by(|info| info.name.cmp(&full.info.name)) {
+                Ok(index) => {
+                    state.order_infos[index] = full.info;
+                    state.orders[index] = full.order;
+                    state.distributions[index] = full.distribution;
+                },
+                Err(index) => {
+                    state.order_infos.insert(index, full.info);
+                    state.orders.insert(index, full.order);
+                    state.distributions.insert(index, full.distribution);
+                },
+            },
+            OrderChange::Remove(name) => {
+                let index = state.order_infos.binary_search_by(|info| info.name.cmp(&name)).unwrap();
+                state.order_infos.remove(index);
+                state.orders.remove(index);
+                state.distributions.remove(index);
+            },
+        }
+
+        for change in distributions {
+            let index = state.order_infos.binary_search_by(|info| info.name.cmp(&change.name)).unwrap();
+            state.distributions[index] = change.distribution;
+        }
+    }
+
+    /// Names and distributions of all orders
+    fn distributions(state: &OrderState) -> Vec<(&str, [usize; 3])> {
+        state.order_infos.iter().zip(&state.distributions).map(|(info, distribution)| (info.name.as_str(), distribution.0)).collect()
+    }
+
+    /// Runs `operation` on `server` and applies the update it returns to a copy of the previous state
+    fn assert_update_applies(server: &mut OrderState, operation: impl FnOnce(&mut OrderState) -> (OrderChange, Vec<DistributionChange>)) {
+        let mut client = OrderState::from_full_data(server.to_full_data());
+
+        let (change, changes) = operation(server);
+        apply(&mut client, change, changes);
+
+        assert_eq!(distributions(&client), distributions(server));
+        assert_eq!(client.version, server.version - 1);
+    }
+
+    fn add<'a>(name: &str, amounts: [usize; 3], config: &'a Config) -> impl FnOnce(&mut OrderState) -> (OrderChange, Vec<DistributionChange>) + 'a {
+        let name = name.to_owned();
+        move |state| {
+            let (full, changes) = state.try_add_order(name, order(amounts, 0.5), config).unwrap();
+            (OrderChange::Set(full), changes)
+        }
+    }
+
+    /// Orders of mixed kinds, so changing one moves slices of the others
+    fn state(config: &Config) -> OrderState {
+        let mut state = OrderState::new(0);
+        for (name, amounts) in [("bob", [4, 3, 0]), ("dave", [3, 0, 4]), ("frank", [0, 5, 2])] {
+            state.try_add_order(name.to_owned(), order(amounts, 0.5), config).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn updates_apply_after_inserted_orders() {
+        let config = Config::default();
+        let mut server = state(&config);
+
+        // Before, between and after the existing orders, shifting their indices
+        assert_update_applies(&mut server, add("alice", [2, 5, 1], &config));
+        assert_update_applies(&mut server, add("carol", [3, 2, 3], &config));
+        assert_update_applies(&mut server, add("zoe", [1, 1, 4], &config));
+
+        assert_eq!(server.order_infos.len(), 6);
+    }
+
+    #[test]
+    fn updates_apply_after_edited_orders() {
+        let config = Config::default();
+        let mut server = state(&config);
+
+        assert_update_applies(&mut server, |state| {
+            let (full, changes) = state.try_edit_order(String::from("dave"), order([7, 1, 0], 0.9), &config).unwrap();
+            (OrderChange::Set(full), changes)
+        });
+        assert_eq!(server.get_order("dave").unwrap().order.amounts.0, [7, 1, 0]);
+    }
+
+    #[test]
+    fn updates_apply_after_deleted_orders() {
+        let config = Config::default();
+        let mut server = state(&config);
+
+        // The first entry, so every index shifts
+        assert_update_applies(&mut server, |state| {
+            let (info, changes) = state.try_delete_order("bob", &config).unwrap();
+            (OrderChange::Remove(info.name), changes)
+        });
+        assert_update_applies(&mut server, |state| {
+            let (info, changes) = state.try_delete_order("frank", &config).unwrap();
+            (OrderChange::Remove(info.name), changes)
+        });
+
+        assert_eq!(distributions(&server).len(), 1);
+    }
+
+    #[test]
+    fn updates_only_contain_changed_distributions() {
+        let config = Config::default();
+        let mut server = state(&config);
+        let before = server.distributions.clone();
+
+        let (_, changes) = server.try_edit_order(String::from("bob"), order([4, 3, 0], 0.5), &config).unwrap();
+
+        assert!(changes.is_empty());
+        assert!(before.iter().zip(&server.distributions).all(|(before, after)| before == after));
+    }
+
+    const ORGANIZER: &str = AppState::TEST_ORGANIZER_TOKEN;
+
+    fn origin() -> Origin {
+        Origin {
+            address: SocketAddr::from(([127, 0, 0, 1], 0)),
+            user_agent: None,
+        }
+    }
+
+    fn request(name: &str, amounts: [usize; 3]) -> OrderRequest {
+        OrderRequest { name: name.to_owned(), order: order(amounts, 0.5) }
+    }
+
+    /// Makes the order of `name` and returns its token
+    async fn make(state: &AppState, name: &str) -> EditToken {
+        let MakeOrderResponse::Success(token) = state.make_order(request(name, [2, 1, 0]), &origin()).await else {
+            panic!("could not make the order of `{name}`");
+        };
+        token
+    }
+
+    async fn amounts(state: &AppState, name: &str) -> [usize; 3] {
+        state.orders.lock().await.get_order(name).unwrap().order.amounts.0
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_and_the_organizer_may_edit() {
+        let state = AppState::temporary("edit-tokens");
+        let token = make(&state, "alice").await;
+        let bob = make(&state, "bob").await;
+
+        for wrong in [None, Some(""), Some(bob.as_str()), Some("guess")] {
+            assert!(matches!(state.edit_order(request("alice", [3, 0, 0]), wrong, None, &origin()).await, EditOrderResponse::InvalidToken));
+        }
+        assert_eq!(amounts(&state, "alice").await, [2, 1, 0]);
+
+        assert!(matches!(state.edit_order(request("alice", [3, 0, 0]), Some(&token), None, &origin()).await, EditOrderResponse::Success));
+        assert!(matches!(state.edit_order(request("alice", [4, 0, 0]), Some(ORGANIZER), None, &origin()).await, EditOrderResponse::Success));
+        assert_eq!(amounts(&state, "alice").await, [4, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_and_the_organizer_may_delete() {
+        let state = AppState::temporary("delete-tokens");
+        let token = make(&state, "alice").await;
+        make(&state, "bob").await;
+
+        assert!(matches!(state.delete_order("alice", None, &origin()).await, DeleteOrderResponse::InvalidToken));
+        assert!(matches!(state.delete_order("alice", Some("guess"), &origin()).await, DeleteOrderResponse::InvalidToken));
+        assert!(matches!(state.delete_order("alice", Some(&token), &origin()).await, DeleteOrderResponse::Success));
+        assert!(matches!(state.delete_order("bob", Some(ORGANIZER), &origin()).await, DeleteOrderResponse::Success));
+        assert!(matches!(state.delete_order("bob", Some(ORGANIZER), &origin()).await, DeleteOrderResponse::NameNotFound));
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_and_the_organizer_may_revert() {
+        let state = AppState::temporary("revert-tokens");
+        let token = make(&state, "alice").await;
+        state.edit_order(request("alice", [3, 0, 0]), Some(&token), None, &origin()).await;
+        state.edit_order(request("alice", [4, 0, 0]), Some(&token), None, &origin()).await;
+
+        assert!(matches!(state.revert_order("alice", Some("guess"), &origin()).await, RevertOrderResponse::InvalidToken));
+        assert!(matches!(state.revert_order("alice", Some(&token), &origin()).await, RevertOrderResponse::Success(order) if order.amounts.0 == [3, 0, 0]));
+        assert!(matches!(state.revert_order("alice", Some(ORGANIZER), &origin()).await, RevertOrderResponse::Success(order) if order.amounts.0 == [2, 1, 0]));
+        assert!(matches!(state.revert_order("alice", Some(&token), &origin()).await, RevertOrderResponse::NothingToRevert));
+    }
+
+    #[tokio::test]
+    async fn orders_without_a_stored_token_are_left_to_the_organizer() {
+        let state = AppState::temporary("missing-token");
+        make(&state, "alice").await;
+        // Like an order restored from a state saved without its token
+        state.tokens.lock().await.clear();
+
+        assert!(matches!(state.edit_order(request("alice", [3, 0, 0]), None, None, &origin()).await, EditOrderResponse::InvalidToken));
+        assert!(matches!(state.edit_order(request("alice", [3, 0, 0]), Some(""), None, &origin()).await, EditOrderResponse::InvalidToken));
+        assert!(matches!(state.identify("alice", "").await, IdentifyResponse::InvalidToken));
+        assert!(matches!(state.delete_order("alice", None, &origin()).await, DeleteOrderResponse::InvalidToken));
+        assert!(matches!(state.delete_order("alice", Some(ORGANIZER), &origin()).await, DeleteOrderResponse::Success));
+    }
+
+    #[tokio::test]
+    async fn saved_tokens_are_restored() {
+        let state = AppState::temporary("saved-tokens");
+        let token = make(&state, "alice").await;
+        state.save().await.unwrap();
+
+        let (orders, tokens) = persistence::load(&state.config.data_dir).unwrap().unwrap();
+        assert_eq!(orders.orders.len(), 1);
+        assert_eq!(tokens.get("alice"), Some(&token));
+    }
+
+    #[tokio::test]
+    async fn rejects_edits_based_on_an_outdated_version() {
+        let state = AppState::temporary("edit-conflict");
+        let token = make(&state, "alice").await;
+        let read = state.orders.lock().await.get_order("alice").unwrap().info.version;
+        make(&state, "bob").await;
+
+        // Changes of other orders do not conflict
+        assert!(matches!(state.edit_order(request("alice", [3, 0, 0]), Some(&token), Some(read), &origin()).await, EditOrderResponse::Success));
+
+        let current = state.orders.lock().await.get_order("alice").unwrap().info.version;
+        let response = state.edit_order(request("alice", [5, 0, 0]), Some(&token), Some(read), &origin()).await;
+        let EditOrderResponse::Conflict(conflicting) = response else {
+            panic!("outdated edit was not rejected");
+        };
+        assert_eq!(conflicting.info.version, current);
+        assert_eq!(conflicting.order.amounts.0, [3, 0, 0]);
+        assert_eq!(amounts(&state, "alice").await, [3, 0, 0]);
+
+        // Checked after the token, so the current order is not revealed to others
+        assert!(matches!(state.edit_order(request("alice", [5, 0, 0]), Some("guess"), Some(read), &origin()).await, EditOrderResponse::InvalidToken));
+        assert!(matches!(state.edit_order(request("alice", [5, 0, 0]), Some(&token), Some(current), &origin()).await, EditOrderResponse::Success));
+    }
+
+    /// Versions of the updates since `version`, `None` if they can not be resumed
+    async fn updates_since(state: &AppState, version: OrderStateVersion) -> Option<Vec<OrderStateVersion>> {
+        let current = state.orders.lock().await.version;
+        let updates = state.updates_since(version, current)?;
+
+        Some(updates.iter().map(|update| update.version().unwrap()).collect())
+    }
+
+    #[tokio::test]
+    async fn resumes_with_the_missed_updates() {
+        let state = AppState::temporary("resume-updates");
+        let token = make(&state, "alice").await;
+        make(&state, "bob").await;
+        state.edit_order(request("alice", [3, 0, 0]), Some(&token), None, &origin()).await;
+
+        assert_eq!(updates_since(&state, 0).await, Some(vec![1, 2, 3]));
+        assert_eq!(updates_since(&state, 1).await, Some(vec![2, 3]));
+        assert_eq!(updates_since(&state, 3).await, Some(vec![]));
+        // From a server that was restarted meanwhile
+        assert_eq!(updates_since(&state, 4).await, None);
+    }
+
+    #[tokio::test]
+    async fn does_not_resume_with_evicted_updates() {
+        let mut state = AppState::temporary("resume-evicted");
+        state.config.resume_capacity = 2;
+        for name in ["alice", "bob", "carol"] {
+            make(&state, name).await;
+        }
+
+        assert_eq!(updates_since(&state, 0).await, None);
+        assert_eq!(updates_since(&state, 1).await, Some(vec![2, 3]));
+    }
+
+    #[tokio::test]
+    async fn does_not_resume_across_a_rollback() {
+        let state = AppState::temporary("resume-rollback");
+        let token = make(&state, "alice").await;
+        make(&state, "bob").await;
+
+        assert!(matches!(state.rollback(1, Some(ORGANIZER), &origin()).await, RollbackResponse::Success));
+        // The rollback is sent as full state, which is not kept with the updates
+        assert_eq!(updates_since(&state, 1).await, None);
+        assert_eq!(updates_since(&state, 2).await, None);
+
+        state.edit_order(request("alice", [3, 0, 0]), Some(&token), None, &origin()).await;
+        assert_eq!(updates_since(&state, 3).await, Some(vec![4]));
+    }
+
+    #[tokio::test]
+    async fn resends_the_announcement_on_resume() {
+        let state = AppState::temporary("resume-announcement");
+        make(&state, "alice").await;
+        state.set_announcement(String::from("order at 7"), Some(ORGANIZER), &origin()).await;
+
+        let orders = state.orders.lock().await;
+        let resumed = state.resume_packages(&orders, Some(0));
+        assert_eq!(resumed.len(), 2);
+        assert_eq!(resumed[0].version(), Some(1));
+        assert!(matches!(serde_json::from_str(resumed[1].json()).unwrap(), ServerPackage::Announcement(announcement) if announcement == "order at 7"));
+
+        // Without a known version everything is resent
+        let full = state.resume_packages(&orders, None);
+        assert_eq!(full.len(), 1);
+        assert!(matches!(serde_json::from_str(full[0].json()).unwrap(), ServerPackage::All(all) if all.announcement == "order at 7" && all.version == 1));
+    }
+}
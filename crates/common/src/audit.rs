@@ -0,0 +1,47 @@
+//! Entries of the append-only log of every accepted mutation
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::orders::{OrderRequest, OrderStateVersion};
+
+/// A single accepted mutation and who made it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditEvent {
+    /// Version of the state after the mutation, announcements do not change it
+    pub version: OrderStateVersion,
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub address: SocketAddr,
+    pub user_agent: Option<String>,
+    /// Whether the organizer token was used instead of the token of the order
+    pub by_organizer: bool,
+    pub change: AuditChange,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum AuditChange {
+    AddOrder(OrderRequest),
+    EditOrder(OrderRequest),
+    DeleteOrder(String),
+    /// The order was restored to the value before its last edit
+    RevertOrder(OrderRequest),
+    /// All orders were reset to the given version, the announcement is kept
+    Rollback(OrderStateVersion),
+    SetAnnouncement(String),
+}
+
+impl AuditChange {
+    /// The order affected by the change, if any
+    pub fn order_name(&self) -> Option<&str> {
+        match self {
+            AuditChange::AddOrder(request) |
+            AuditChange::EditOrder(request) |
+            AuditChange::RevertOrder(request) => Some(&request.name),
+            AuditChange::DeleteOrder(name) => Some(name),
+            AuditChange::Rollback(_) |
+            AuditChange::SetAnnouncement(_) => None,
+        }
+    }
+}
@@ -2,7 +2,59 @@ use std::borrow::Cow;
 
 use serde::{Deserialize, Serialize};
 
-use crate::orders::{Distribution, FullOrder, Order, OrderInfo, OrderRequest, OrderStateVersion, PizzaAmount, PizzaKindArray};
+use crate::{audit::AuditEvent, export::ExportFormat, orders::{Distribution, FullOrder, Order, OrderInfo, OrderRequest, OrderStateVersion, PizzaAmount, PizzaKindArray}, validation::ValidationError};
+
+/// Secret returned on order creation, required to edit or delete that order
+pub type EditToken = String;
+
+pub type ProtocolVersion = u32;
+
+/// Incremented on every incompatible change of [`ClientPackage`] or [`ServerPackage`],
+/// only [`Hello`] has to stay the same across all versions.
+/// New packages a client has to opt into with a [`Capability`] do not need a new version.
+///
+/// - 1: [`Hello`] handshake
+/// - 2: [`ServerPackage::Update`] with an [`OrderChange`] and only the changed distributions as [`DistributionChange`]s
+/// - 3: [`Request`] IDs echoed in [`ServerPackage::Response`],
+///   [`MakeOrderResponse::TooManyOrders`], [`ErrorCode::RateLimited`] and the `Invalid` responses with a [`ValidationError`]
+pub const PROTOCOL_VERSION: ProtocolVersion = 3;
+
+/// WebSocket close codes in the range reserved for applications
+pub mod close_code {
+    /// The client did not start with a [`Hello`](super::Hello)
+    pub const HANDSHAKE_FAILED: u16 = 4000;
+    /// The client speaks a different [`PROTOCOL_VERSION`](super::PROTOCOL_VERSION)
+    pub const INCOMPATIBLE_VERSION: u16 = 4001;
+}
+
+/// Optional protocol features
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Handles [`ServerPackage::Announcement`]
+    Announcements,
+    /// Handles [`ServerPackage::Presence`]
+    Presence,
+    /// Any capability added by a newer version
+    #[serde(other)]
+    Unknown,
+}
+
+/// First message of both sides, the server sends it right after connecting and waits for the one of the client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: ProtocolVersion,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    /// The handshake of this version
+    pub fn current() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Announcements, Capability::Presence],
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct FullOrderData<'a> {
@@ -13,52 +65,258 @@ pub struct FullOrderData<'a> {
 
     pub config: PizzaKindArray<PizzaAmount>,
     pub distributions: Cow<'a, [Distribution]>,
-    pub valid_distributions: bool
+    pub valid_distributions: bool,
+
+    pub announcement: Cow<'a, str>,
+}
+
+/// Chosen by the client to match a [`ServerPackage::Response`] to its request
+pub type RequestId = u64;
+
+/// Chosen by the client, at most 64 bytes, to mark retries of the same mutation
+pub type IdempotencyKey = String;
+
+/// Every package of the client after the handshake
+#[derive(Serialize, Deserialize)]
+pub struct Request {
+    /// Echoed in the response, or in the [`ServerError`] if the package was rejected
+    pub id: RequestId,
+    /// The server answers requests with a key it has seen recently with the original response instead of running them again,
+    /// only used for packages that modify the state
+    #[serde(default)]
+    pub idempotency_key: Option<IdempotencyKey>,
+    pub package: ClientPackage,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum ClientPackage {
+    /// Only valid as the very first message, which is sent without a [`Request`] around it
+    Hello(Hello),
     MakeOrder(OrderRequest),
-    EditOrder(OrderRequest),
+    /// Requires the token of the order or the organizer token
+    EditOrder {
+        request: OrderRequest,
+        token: EditToken,
+        /// [`OrderInfo::version`] the edit is based on, the edit is rejected if the order changed since
+        #[serde(default)]
+        expected_version: Option<OrderStateVersion>,
+    },
+    /// Requires the token of the order or the organizer token
+    DeleteOrder {
+        name: String,
+        token: EditToken,
+    },
+    /// Restores the order before its last edit, requires the token of the order or the organizer token
+    RevertOrder {
+        name: String,
+        token: EditToken,
+    },
     GetOrder(String), // Currently redundant, since client should keep track of the servers state
     RequestAll,
+    /// Requires the organizer token
+    SetAnnouncement {
+        announcement: String,
+        token: EditToken,
+    },
+    /// Marks the connection as belonging to the order, requires the token of the order itself
+    Identify {
+        name: String,
+        token: EditToken,
+    },
+    /// Resets all orders to how they were at `version`, requires the organizer token.
+    /// Restored orders keep their token unless the name was reused since, but can not be reverted to earlier values.
+    Rollback {
+        version: OrderStateVersion,
+        token: EditToken,
+    },
+    /// Requires the organizer token, `name` restricts the events to a single order
+    QueryEvents {
+        name: Option<String>,
+        token: EditToken,
+    },
+    /// Summary of the round for the restaurant or the people who ordered
+    Export(ExportFormat),
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum ServerPackage<'a> {
-    Response(Response),
+    /// Always the very first message, followed by [`ServerPackage::All`] once the client answered with its own
+    Hello(Hello),
+    /// Answer to the [`Request`] with the given ID
+    Response {
+        id: RequestId,
+        response: Response,
+    },
+    /// Only applies to the state at `version - 1`, otherwise the client has to catch up with [`ClientPackage::RequestAll`]
     Update {
-        order: FullOrder,
+        change: OrderChange,
 
         version: OrderStateVersion,
         config: PizzaKindArray<PizzaAmount>,
-        distributions: Cow<'a, [Distribution]>,
+        /// Only the distributions that differ from the previous version
+        distributions: Vec<DistributionChange>,
         distributions_valid: bool
     },
-    All(FullOrderData<'a>)
+    All(FullOrderData<'a>),
+    Announcement(Cow<'a, str>),
+    /// Names of all orders with at least one open connection, sorted
+    Presence(Vec<String>),
+    /// Sent instead of a response if a request could not be processed at all
+    Error(ServerError),
 }
 
-#[derive(Serialize, Deserialize)]
+impl ServerPackage<'_> {
+    /// Version of the order state the package brings the client to, if it changes the orders
+    pub fn version(&self) -> Option<OrderStateVersion> {
+        match self {
+            ServerPackage::Update { version, .. } => Some(*version),
+            ServerPackage::All(all) => Some(all.version),
+            ServerPackage::Hello(_) |
+            ServerPackage::Response { .. } |
+            ServerPackage::Announcement(_) |
+            ServerPackage::Presence(_) |
+            ServerPackage::Error(_) => None,
+        }
+    }
+
+    /// Capability the client has to declare in its [`Hello`] to be sent the package
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            ServerPackage::Announcement(_) => Some(Capability::Announcements),
+            ServerPackage::Presence(_) => Some(Capability::Presence),
+            ServerPackage::Hello(_) |
+            ServerPackage::Response { .. } |
+            ServerPackage::Update { .. } |
+            ServerPackage::All(_) |
+            ServerPackage::Error(_) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request is not a valid `ClientPackage`
+    MalformedRequest,
+    /// The message type is not used by the protocol
+    UnsupportedMessage,
+    /// The first message was not a [`ClientPackage::Hello`], or a later one was
+    HandshakeFailed,
+    /// The client speaks a different [`PROTOCOL_VERSION`]
+    IncompatibleVersion,
+    /// The client sent too many messages or orders, the message was dropped
+    RateLimited,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    /// The rejected [`Request`], if it could be decoded
+    pub request: Option<RequestId>,
+    /// Human readable description, not meant to be matched on
+    pub message: String,
+}
+
+/// The modification of a single order contained in a [`ServerPackage::Update`]
+#[derive(Serialize, Deserialize, Clone)]
+pub enum OrderChange {
+    /// The order was added or replaced
+    Set(FullOrder),
+    /// The order with the given name was removed
+    Remove(String),
+}
+
+/// New distribution of the order with the given name
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DistributionChange {
+    pub name: String,
+    pub distribution: Distribution,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum Response {
     MakeOrder(MakeOrderResponse),
     EditOrder(EditOrderResponse),
+    DeleteOrder(DeleteOrderResponse),
+    RevertOrder(RevertOrderResponse),
     GetOrder(GetOrderResponse),
+    SetAnnouncement(SetAnnouncementResponse),
+    Identify(IdentifyResponse),
+    QueryEvents(QueryEventsResponse),
+    Rollback(RollbackResponse),
+    Export(String),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum MakeOrderResponse {
-    Success,
+    Success(EditToken),
     NameAlreadyRegistered,
+    /// The round reached its maximum number of orders
+    TooManyOrders,
+    Invalid(ValidationError),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub enum EditOrderResponse {
     Success,
     NameNotFound,
+    InvalidToken,
+    Invalid(ValidationError),
+    /// The order changed since the expected version, contains its current state
+    Conflict(FullOrder),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+pub enum DeleteOrderResponse {
+    Success,
+    NameNotFound,
+    InvalidToken,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum RevertOrderResponse {
+    /// The restored order
+    Success(Order),
+    NameNotFound,
+    InvalidToken,
+    /// The order was not edited since it was made, or all its edits were reverted already
+    NothingToRevert,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub enum GetOrderResponse {
     Success(FullOrder),
     NameNotFound,
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum SetAnnouncementResponse {
+    Success,
+    InvalidToken,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum QueryEventsResponse {
+    /// Oldest event first
+    Success(Vec<AuditEvent>),
+    InvalidToken,
+    /// The event log could not be read
+    Unavailable,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum RollbackResponse {
+    /// The orders were reset and broadcast with [`ServerPackage::All`] under a new version
+    Success,
+    InvalidToken,
+    /// The version is newer than the current one or older than the event log
+    VersionNotFound,
+    /// The event log could not be read or replayed
+    Unavailable,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum IdentifyResponse {
+    Success,
+    NameNotFound,
+    InvalidToken,
+}
@@ -0,0 +1,72 @@
+//! Wire formats of [`ClientPackage`](crate::communication::ClientPackage) and [`ServerPackage`](crate::communication::ServerPackage)
+//!
+//! The format is negotiated via the WebSocket subprotocol, connections without one use JSON.
+//! Clients list the subprotocols they support in their order of preference.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Sent as text messages
+    #[default]
+    Json,
+    /// Sent as binary messages, considerably smaller for large snapshots
+    MessagePack,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    MessagePack(#[from] rmp_serde::encode::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    MessagePack(#[from] rmp_serde::decode::Error),
+}
+
+impl Encoding {
+    /// All supported encodings. The order carries no preference,
+    /// the server picks the first subprotocol in the client's list that it supports.
+    pub const ALL: [Encoding; 2] = [Encoding::MessagePack, Encoding::Json];
+
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            Encoding::Json => "pizzabot.json",
+            Encoding::MessagePack => "pizzabot.msgpack",
+        }
+    }
+
+    pub fn from_subprotocol(subprotocol: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|encoding| encoding.subprotocol() == subprotocol)
+    }
+
+    /// Whether packages are sent as binary instead of text messages
+    pub fn is_binary(self) -> bool {
+        match self {
+            Encoding::Json => false,
+            Encoding::MessagePack => true,
+        }
+    }
+
+    /// Serializes `value`, the result is valid UTF-8 unless [`Encoding::is_binary`]
+    pub fn encode(self, value: &impl Serialize) -> Result<Vec<u8>, EncodeError> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            // Structs as maps, so fields can be added without breaking older clients
+            Encoding::MessagePack => Ok(rmp_serde::to_vec_named(value)?),
+        }
+    }
+
+    pub fn decode<'de, T: Deserialize<'de>>(self, bytes: &'de [u8]) -> Result<T, DecodeError> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(bytes)?),
+            Encoding::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+        }
+    }
+}
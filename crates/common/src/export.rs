@@ -0,0 +1,196 @@
+//! Summaries of the current round for calling the restaurant and collecting the money
+
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{globals::PizzaConfig, orders::{OrderState, PizzaKind, Price}};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Whole pizzas per kind, the total and the announcement, short enough to read out on the phone
+    Text,
+    /// One row per order with its slices per kind, price and whether it was paid
+    Csv,
+    /// The summary followed by a table of every order
+    Markdown,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Text => "text/plain; charset=utf-8",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+}
+
+pub fn export(state: &OrderState, config: &PizzaConfig, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Text => text(state, config),
+        ExportFormat::Csv => csv(state, config),
+        ExportFormat::Markdown => markdown(state, config),
+    }
+}
+
+/// Price of `slices` slices
+fn slice_price(slices: usize, config: &PizzaConfig) -> Price {
+    Price { cents: slices * config.price_per_piece.cents }
+}
+
+/// What the restaurant charges for all whole pizzas
+fn total_price(state: &OrderState, config: &PizzaConfig) -> Price {
+    let pizzas: usize = state.config.map(usize::from).sum();
+    slice_price(pizzas * config.pieces_per_pizza as usize, config)
+}
+
+fn format_price(price: &Price) -> String {
+    format!("{}.{:02}", price.cents / 100, price.cents % 100)
+}
+
+fn text(state: &OrderState, config: &PizzaConfig) -> String {
+    let mut out = String::new();
+
+    for (kind, amount) in PizzaKind::All.into_iter().zip(state.config) {
+        if amount > 0 {
+            let _ = writeln!(out, "{amount}x {}", kind.name());
+        }
+    }
+    let pizzas: usize = state.config.map(usize::from).sum();
+    let _ = writeln!(out, "Total: {pizzas} pizzas, {}", format_price(&total_price(state, config)));
+
+    if !state.announcement.is_empty() {
+        let _ = writeln!(out, "Notes: {}", state.announcement);
+    }
+
+    out
+}
+
+/// Quotes fields containing separators, quotes or line breaks as described in RFC 4180
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", field.replace('"', "\"\""))
+    }
+
+    field.to_owned()
+}
+
+fn csv(state: &OrderState, config: &PizzaConfig) -> String {
+    let mut out = String::from("name");
+    for kind in PizzaKind::All {
+        let _ = write!(out, ",{}", kind.name());
+    }
+    out.push_str(",price,paid\r\n");
+
+    for (info, distribution) in state.order_infos.iter().zip(&state.distributions) {
+        out.push_str(&csv_field(&info.name));
+        for slices in distribution.0 {
+            let _ = write!(out, ",{slices}");
+        }
+        let _ = write!(out, ",{},{}\r\n", format_price(&slice_price(distribution.sum::<usize>(), config)), info.has_paid);
+    }
+
+    out
+}
+
+/// Escapes characters with a meaning inside of table cells
+fn markdown_cell(cell: &str) -> String {
+    cell.replace('\\', "\\\\").replace('|', "\\|").replace(['\n', '\r'], " ")
+}
+
+fn markdown(state: &OrderState, config: &PizzaConfig) -> String {
+    let mut out = String::from("## Order\n\n| Kind | Pizzas |\n| --- | ---: |\n");
+
+    for (kind, amount) in PizzaKind::All.into_iter().zip(state.config) {
+        let _ = writeln!(out, "| {} | {amount} |", kind.name());
+    }
+    let pizzas: usize = state.config.map(usize::from).sum();
+    let _ = writeln!(out, "\n**Total:** {pizzas} pizzas, {}", format_price(&total_price(state, config)));
+
+    if !state.announcement.is_empty() {
+        let _ = writeln!(out, "\n**Notes:** {}", state.announcement);
+    }
+
+    out.push_str("\n## People\n\n| Name |");
+    for kind in PizzaKind::All {
+        let _ = write!(out, " {} |", kind.name());
+    }
+    out.push_str(" Price | Paid |\n| --- |");
+    for _ in PizzaKind::All {
+        out.push_str(" ---: |");
+    }
+    out.push_str(" ---: | --- |\n");
+
+    for (info, distribution) in state.order_infos.iter().zip(&state.distributions) {
+        let _ = write!(out, "| {} |", markdown_cell(&info.name));
+        for slices in distribution.0 {
+            let _ = write!(out, " {slices} |");
+        }
+        let _ = writeln!(out, " {} | {} |", format_price(&slice_price(distribution.sum::<usize>(), config)), if info.has_paid { "yes" } else { "no" });
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::orders::{OrderInfo, PizzaKindArray};
+
+    use super::*;
+
+    /// A round with a single order of `name` and two whole meat pizzas
+    fn state(name: &str) -> OrderState {
+        let mut state = OrderState::new(1);
+        state.order_infos.push(OrderInfo {
+            name: name.to_owned(),
+            has_paid: true,
+            price: Price { cents: 0 },
+            version: 1,
+        });
+        state.distributions.push(PizzaKindArray([4, 0, 0]));
+        state.config = PizzaKindArray([2, 0, 0]);
+        state
+    }
+
+    #[test]
+    fn csv_quotes_separators_quotes_and_line_breaks() {
+        assert_eq!(csv_field("alice"), "alice");
+        assert_eq!(csv_field("doe, john"), "\"doe, john\"");
+        assert_eq!(csv_field("the \"boss\""), "\"the \"\"boss\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("a|b"), "a|b");
+    }
+
+    #[test]
+    fn csv_has_a_column_per_kind_and_a_row_per_order() {
+        let csv = export(&state("doe, \"john\""), &PizzaConfig::default(), ExportFormat::Csv);
+
+        assert_eq!(csv, "name,meat,vegetarian,vegan,price,paid\r\n\"doe, \"\"john\"\"\",4,0,0,6.00,true\r\n");
+    }
+
+    #[test]
+    fn markdown_escapes_table_cells() {
+        assert_eq!(markdown_cell("a|b"), "a\\|b");
+        assert_eq!(markdown_cell("back\\slash"), "back\\\\slash");
+        assert_eq!(markdown_cell("two\r\nlines"), "two  lines");
+        assert_eq!(markdown_cell("doe, \"john\""), "doe, \"john\"");
+    }
+
+    #[test]
+    fn markdown_keeps_names_in_their_row() {
+        let markdown = export(&state("a|b\nc"), &PizzaConfig::default(), ExportFormat::Markdown);
+
+        assert!(markdown.contains("| meat | 2 |\n"));
+        assert!(markdown.contains("| Name | meat | vegetarian | vegan | Price | Paid |\n"));
+        assert!(markdown.contains("\n| a\\|b c | 4 | 0 | 0 | 6.00 | yes |\n"));
+    }
+
+    #[test]
+    fn text_uses_the_kind_names() {
+        let text = export(&state("alice"), &PizzaConfig::default(), ExportFormat::Text);
+
+        assert_eq!(text, "2x meat\nTotal: 2 pizzas, 45.00\n");
+    }
+}
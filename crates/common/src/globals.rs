@@ -7,10 +7,22 @@ pub struct WebSiteConfig {
     pizza: PizzaConfig, // TODO Other
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct PizzaConfig {
-    width_of_piece_in_cm: u8,
-    length_of_piece_in_cm: u8,
-    price_per_piece: Price,
-    pieces_per_pizza: u16,
+    pub width_of_piece_in_cm: u8,
+    pub length_of_piece_in_cm: u8,
+    pub price_per_piece: Price,
+    pub pieces_per_pizza: u16,
+}
+
+impl Default for PizzaConfig {
+    fn default() -> Self {
+        Self {
+            width_of_piece_in_cm: 10,
+            length_of_piece_in_cm: 10,
+            price_per_piece: Price { cents: 150 },
+            pieces_per_pizza: 15,
+        }
+    }
 }
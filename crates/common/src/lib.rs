@@ -1,7 +1,12 @@
 #![allow(non_upper_case_globals)]
 
 pub mod archive;
+pub mod audit;
 pub mod globals;
 pub mod orders;
 pub mod temp_globals;
-pub mod communication;
\ No newline at end of file
+pub mod communication;
+pub mod encoding;
+pub mod export;
+pub mod syntax;
+pub mod validation;
\ No newline at end of file
@@ -23,7 +23,9 @@ pub struct OrderState {
 
     pub config: PizzaKindArray<PizzaAmount>,
     pub distributions: Vec<Distribution>,
-    pub distributions_valid: bool
+    pub distributions_valid: bool,
+
+    pub announcement: String,
 }
 
 impl OrderState {
@@ -36,7 +38,9 @@ impl OrderState {
 
             config: PizzaKindArray::splat(0),
             distributions: Vec::new(),
-            distributions_valid: true
+            distributions_valid: true,
+
+            announcement: String::new(),
         }
     }
 
@@ -48,28 +52,30 @@ impl OrderState {
             config: all.config,
             distributions: all.distributions.into_owned(),
             distributions_valid: all.valid_distributions,
+            announcement: all.announcement.into_owned(),
         }
     }
 
-    pub fn to_full_data(&self) -> FullOrderData {
+    pub fn to_full_data(&self) -> FullOrderData<'_> {
         FullOrderData {
             version: self.version,
             order_infos: Cow::Borrowed(&self.order_infos),
             orders: Cow::Borrowed(&self.orders),
             config: self.config,
             distributions: Cow::Borrowed(&self.distributions),
-            valid_distributions: self.distributions_valid
+            valid_distributions: self.distributions_valid,
+            announcement: Cow::Borrowed(&self.announcement),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct OrderRequest {
     pub name: String,
     pub order: Order
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct FullOrder {
     pub info: OrderInfo,
     pub order: Order,
@@ -82,6 +88,9 @@ pub struct OrderInfo {
     pub name: String,
     pub has_paid: bool,
     pub price: Price,
+    /// Version of the state in which the order was last made or edited
+    #[serde(default)]
+    pub version: OrderStateVersion,
 }
 
 /// Base Order
@@ -91,6 +100,7 @@ pub struct Order {
     pub preference: Preference
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PizzaKind {
     Meat,
     Vegetarian,
@@ -99,32 +109,57 @@ pub enum PizzaKind {
 
 impl PizzaKind {
     pub const Length: usize = 3;
+    /// Every kind in the order of the elements of a [`PizzaKindArray`]
+    pub const All: [PizzaKind; PizzaKind::Length] = [PizzaKind::Meat, PizzaKind::Vegetarian, PizzaKind::Vegan];
+
+    /// Name on the menu, as accepted by [`PizzaKind::from_name`]
+    pub fn name(self) -> &'static str {
+        match self {
+            PizzaKind::Meat => "meat",
+            PizzaKind::Vegetarian => "vegetarian",
+            PizzaKind::Vegan => "vegan",
+        }
+    }
+
+    /// Shorter names accepted in addition to [`PizzaKind::name`]
+    pub fn aliases(self) -> &'static [&'static str] {
+        match self {
+            PizzaKind::Meat => &["m"],
+            PizzaKind::Vegetarian => &["veg", "veggie", "v"],
+            PizzaKind::Vegan => &["vg"],
+        }
+    }
+
+    /// Looks up a kind by its name or one of its aliases, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+
+        PizzaKind::All.into_iter().find(|kind| kind.name() == name || kind.aliases().contains(&name.as_str()))
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub struct PizzaKindArray<T>(pub [T; PizzaKind::Length]);
 
 impl<T> PizzaKindArray<T> {
     /// Creates an array where each element is value
     pub fn splat(value: T) -> Self where T: Clone {
-        if PizzaKind::Length == 0 {
-            // SAFETY: since length is zero, it is already initialized
-            return Self(unsafe {MaybeUninit::uninit().assume_init()})
-        }
+        // Element 0 is always written below
+        const { assert!(PizzaKind::Length > 0) };
 
         // SAFETY: transposing MaybeUninit<[T; n]> to [MaybeUninit<T>; n] is always safe, since no invalid memory can be read
         let mut values: [MaybeUninit<T>; PizzaKind::Length] = unsafe { MaybeUninit::uninit().assume_init() };
 
         // iterate starting from 1 and later move value into element 0, so as to avoid an unnecessary clone and drop
-        for i in 1..PizzaKind::Length {
-            values[i].write(value.clone());
+        for element in values.iter_mut().skip(1) {
+            element.write(value.clone());
         }
         values[0].write(value);
 
         // SAFETY: since every element is initialized and MaybeUninit<T> has the same size, alignment and ABI as T, we can safely convert the array
         // raw pointer conversion needed, since std::mem::transmute does not work in generic code
         // the read is safe, since MaybeUninit<T> will never get dropped
-        return Self(unsafe { (values.as_mut_ptr() as *mut [T; PizzaKind::Length]).read() })
+        Self(unsafe { (values.as_mut_ptr() as *mut [T; PizzaKind::Length]).read() })
     }
 
     /// Maps the array elementwise using the provided function
@@ -147,7 +182,7 @@ impl<T> PizzaKindArray<T> {
     /// Combines each element to a single value using the provided function, assuming `TypeKind::Length > 0`
     pub fn reduce(self, f: impl Fn(T, T) -> T) -> T {
         let Some(acc) = self.0.into_iter().reduce(f) else {unreachable!()};
-        return acc
+        acc
     }
 
     /// Sums up all elements
@@ -0,0 +1,213 @@
+//! Compact text syntax for orders like `3 meat, 2 veg; pref 0.4`, shared by the CLI, the chat bot and the HTTP API
+//!
+//! An order is a list of items separated by commas, semicolons or whitespace.
+//! Each item is either an amount of slices followed by a kind, see [`PizzaKind::from_name`],
+//! or `pref` followed by the preference from 0 for the shape to 1 for the amount.
+//! Amounts of the same kind add up, the preference defaults to [`DEFAULT_PREFERENCE`].
+
+use crate::{orders::{Distribution, Order, OrderAmount, OrderRequest, PizzaKind, PizzaKindArray, Preference}, validation::{self, ValidationError}};
+
+/// Used if an order does not contain `pref`
+pub const DEFAULT_PREFERENCE: Preference = 0.5;
+
+const PREFERENCE_KEYWORDS: [&str; 2] = ["pref", "preference"];
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ParseError {
+    #[error("the order is empty")]
+    Empty,
+    #[error("expected an amount or `pref`, found `{0}`")]
+    UnexpectedWord(String),
+    #[error("`{0}` has to be followed by a kind, one of {kinds}", kinds = kind_names())]
+    MissingKind(OrderAmount),
+    #[error("unknown kind `{0}`, expected one of {kinds}", kinds = kind_names())]
+    UnknownKind(String),
+    #[error("`pref` has to be followed by a number between 0 and 1")]
+    MissingPreference,
+    #[error("`pref` was given more than once")]
+    RepeatedPreference,
+    #[error("invalid order, {0}")]
+    Invalid(#[from] ValidationError),
+}
+
+/// Every kind with its aliases, for help texts and errors
+pub fn kind_names() -> String {
+    PizzaKind::All.into_iter()
+        .map(|kind| match kind.aliases() {
+            [] => kind.name().to_owned(),
+            aliases => format!("{} ({})", kind.name(), aliases.join(", ")),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parses the syntax described in the [module documentation](self), without checking the limits of [`validation`]
+pub fn parse_order(text: &str) -> Result<Order, ParseError> {
+    let mut amounts: Distribution = PizzaKindArray::splat(0);
+    let mut preference = None;
+
+    let mut words = text.split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .peekable();
+
+    if words.peek().is_none() {
+        return Err(ParseError::Empty)
+    }
+
+    while let Some(word) = words.next() {
+        // Allows `pref 0.4`, `pref: 0.4` and `pref=0.4`
+        let (keyword, value) = word.split_once([':', '=']).unwrap_or((word, ""));
+        if PREFERENCE_KEYWORDS.contains(&keyword.to_lowercase().as_str()) {
+            if preference.is_some() {
+                return Err(ParseError::RepeatedPreference)
+            }
+
+            let value = match value {
+                "" => words.next().unwrap_or(""),
+                value => value,
+            };
+            let Ok(value) = value.parse::<Preference>() else {
+                return Err(ParseError::MissingPreference)
+            };
+            preference = Some(value);
+            continue
+        }
+
+        // Accepts `3x meat` as well
+        let Ok(amount) = word.trim_end_matches(['x', 'X']).parse::<OrderAmount>() else {
+            return Err(ParseError::UnexpectedWord(word.to_owned()))
+        };
+        let Some(kind) = words.next() else {
+            return Err(ParseError::MissingKind(amount))
+        };
+        let Some(kind) = PizzaKind::from_name(kind) else {
+            return Err(ParseError::UnknownKind(kind.to_owned()))
+        };
+
+        let index = kind as usize;
+        amounts.0[index] = amounts.0[index].saturating_add(amount);
+    }
+
+    Ok(Order {
+        amounts,
+        preference: preference.unwrap_or(DEFAULT_PREFERENCE),
+    })
+}
+
+/// Parses the order and validates it together with the name, like [`validation::validate_request`]
+pub fn parse_request(name: &str, text: &str) -> Result<OrderRequest, ParseError> {
+    let order = parse_order(text)?;
+
+    Ok(validation::validate_request(OrderRequest {
+        name: name.to_owned(),
+        order,
+    })?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Slices per kind of the parsed order, since [`Order`] can not be compared directly
+    fn amounts(text: &str) -> [OrderAmount; PizzaKind::Length] {
+        match parse_order(text) {
+            Ok(order) => order.amounts.0,
+            Err(err) => panic!("could not parse `{text}`: {err}"),
+        }
+    }
+
+    fn preference(text: &str) -> Preference {
+        match parse_order(text) {
+            Ok(order) => order.preference,
+            Err(err) => panic!("could not parse `{text}`: {err}"),
+        }
+    }
+
+    #[test]
+    fn kinds_are_found_by_name_and_alias_ignoring_case() {
+        assert_eq!(amounts("1 meat 2 vegetarian 3 vegan"), [1, 2, 3]);
+        assert_eq!(amounts("1 m 2 veg 3 vg"), [1, 2, 3]);
+        assert_eq!(amounts("1 M 2 Veggie 3 VG"), [1, 2, 3]);
+        assert_eq!(amounts("2 v"), [0, 2, 0]);
+    }
+
+    #[test]
+    fn items_are_separated_by_commas_semicolons_or_whitespace() {
+        assert_eq!(amounts("3 meat, 2 veg; pref 0.4"), [3, 2, 0]);
+        assert_eq!(amounts("3 meat;2 veg,1 vegan"), [3, 2, 1]);
+        assert_eq!(amounts(" 3\tmeat \n 2 veg "), [3, 2, 0]);
+        assert_eq!(amounts("3x meat, 2X veg"), [3, 2, 0]);
+    }
+
+    #[test]
+    fn amounts_of_the_same_kind_add_up() {
+        assert_eq!(amounts("2 meat, 3 m"), [5, 0, 0]);
+        assert_eq!(amounts(&format!("{} meat 1 meat", OrderAmount::MAX)), [OrderAmount::MAX, 0, 0]);
+    }
+
+    #[test]
+    fn preference_can_be_written_in_several_ways() {
+        assert_eq!(preference("2 meat"), DEFAULT_PREFERENCE);
+        assert_eq!(preference("2 meat pref 0.4"), 0.4);
+        assert_eq!(preference("2 meat; preference 0.4"), 0.4);
+        assert_eq!(preference("2 meat pref: 0.4"), 0.4);
+        assert_eq!(preference("2 meat pref=0.4"), 0.4);
+        assert_eq!(preference("PREF 0.4 2 meat"), 0.4);
+    }
+
+    #[test]
+    fn empty_orders_are_rejected() {
+        for text in ["", "   ", ",;, ;"] {
+            assert_eq!(parse_order(text).err(), Some(ParseError::Empty), "{text:?}");
+        }
+        assert_eq!(ParseError::Empty.to_string(), "the order is empty");
+    }
+
+    #[test]
+    fn unknown_words_and_kinds_are_named_in_the_error() {
+        let err = parse_order("2 fish").err().unwrap();
+        assert_eq!(err, ParseError::UnknownKind(String::from("fish")));
+        assert_eq!(err.to_string(), "unknown kind `fish`, expected one of meat (m), vegetarian (veg, veggie, v), vegan (vg)");
+
+        let err = parse_order("meat 2").err().unwrap();
+        assert_eq!(err, ParseError::UnexpectedWord(String::from("meat")));
+        assert_eq!(err.to_string(), "expected an amount or `pref`, found `meat`");
+
+        let err = parse_order("2 meat, 3").err().unwrap();
+        assert_eq!(err, ParseError::MissingKind(3));
+        assert_eq!(err.to_string(), "`3` has to be followed by a kind, one of meat (m), vegetarian (veg, veggie, v), vegan (vg)");
+    }
+
+    #[test]
+    fn preference_has_to_be_a_number_given_once() {
+        for text in ["2 meat pref", "2 meat pref:", "2 meat pref much", "2 meat pref=0.4.1"] {
+            assert_eq!(parse_order(text).err(), Some(ParseError::MissingPreference), "{text:?}");
+        }
+        assert_eq!(ParseError::MissingPreference.to_string(), "`pref` has to be followed by a number between 0 and 1");
+
+        let err = parse_order("pref 0.4 2 meat pref 0.6").err().unwrap();
+        assert_eq!(err, ParseError::RepeatedPreference);
+        assert_eq!(err.to_string(), "`pref` was given more than once");
+    }
+
+    #[test]
+    fn limits_are_only_checked_when_parsing_requests() {
+        assert_eq!(preference("2 meat pref 1.5"), 1.5);
+        assert!(preference("2 meat pref NaN").is_nan());
+
+        for text in ["2 meat pref 1.5", "2 meat pref -0.1", "2 meat pref NaN"] {
+            let err = parse_request("alice", text).err().unwrap();
+            assert_eq!(err, ParseError::Invalid(ValidationError::InvalidPreference), "{text:?}");
+            assert_eq!(err.to_string(), "invalid order, the preference must be a number between 0 and 1");
+        }
+
+        assert_eq!(parse_request("alice", "0 meat").err(), Some(ParseError::Invalid(ValidationError::NoSlices)));
+        assert_eq!(parse_request(" ", "2 meat").err(), Some(ParseError::Invalid(ValidationError::EmptyName)));
+
+        let Ok(request) = parse_request("  alice ", "2 meat; pref 0.4") else {
+            panic!("valid request was rejected");
+        };
+        assert_eq!(request.name, "alice");
+        assert_eq!(request.order.amounts.0, [2, 0, 0]);
+    }
+}
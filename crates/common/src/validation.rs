@@ -0,0 +1,136 @@
+//! Checks on user input, shared so clients can reject invalid orders before sending them
+
+use serde::{Deserialize, Serialize};
+
+use crate::orders::{Distribution, Order, OrderAmount, OrderRequest, PizzaKind, Preference};
+
+/// Maximum length of a name in characters, after normalization
+pub const MAX_NAME_LENGTH: usize = 32;
+/// Maximum number of slices of a single kind in one order
+pub const MAX_AMOUNT_PER_KIND: OrderAmount = 32;
+/// Maximum number of slices in one order
+pub const MAX_TOTAL_AMOUNT: OrderAmount = 48;
+
+/// Reason for rejecting an order
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ValidationError {
+    #[error("the name must not be empty")]
+    EmptyName,
+    #[error("the name must not be longer than {MAX_NAME_LENGTH} characters")]
+    NameTooLong,
+    #[error("the name must not contain control characters")]
+    InvalidCharacter,
+    #[error("at most {MAX_AMOUNT_PER_KIND} slices of kind {} can be ordered", .0.name())]
+    TooManyOfKind(PizzaKind),
+    #[error("at most {MAX_TOTAL_AMOUNT} slices can be ordered in total")]
+    TooManyInTotal,
+    #[error("at least one slice has to be ordered")]
+    NoSlices,
+    #[error("the preference must be a number between 0 and 1")]
+    InvalidPreference,
+}
+
+/// Trims the name and collapses all whitespace to single spaces
+pub fn normalize_name(name: &str) -> Result<String, ValidationError> {
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if name.is_empty() {
+        return Err(ValidationError::EmptyName)
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ValidationError::NameTooLong)
+    }
+    if name.chars().any(char::is_control) {
+        return Err(ValidationError::InvalidCharacter)
+    }
+
+    Ok(name)
+}
+
+pub fn validate_amounts(amounts: &Distribution) -> Result<(), ValidationError> {
+    for (kind, &amount) in PizzaKind::All.into_iter().zip(&amounts.0) {
+        if amount > MAX_AMOUNT_PER_KIND {
+            return Err(ValidationError::TooManyOfKind(kind))
+        }
+    }
+
+    // Every amount is capped already, so the sum can not overflow
+    let total: OrderAmount = amounts.sum();
+    if total > MAX_TOTAL_AMOUNT {
+        return Err(ValidationError::TooManyInTotal)
+    }
+    if total == 0 {
+        return Err(ValidationError::NoSlices)
+    }
+
+    Ok(())
+}
+
+pub fn validate_preference(preference: Preference) -> Result<(), ValidationError> {
+    // Also rejects NaN, for which every comparison is false
+    if !(0.0..=1.0).contains(&preference) {
+        return Err(ValidationError::InvalidPreference)
+    }
+
+    Ok(())
+}
+
+pub fn validate_order(order: &Order) -> Result<(), ValidationError> {
+    validate_amounts(&order.amounts)?;
+    validate_preference(order.preference)
+}
+
+/// Validates the order and normalizes the name of the request
+pub fn validate_request(request: OrderRequest) -> Result<OrderRequest, ValidationError> {
+    validate_order(&request.order)?;
+
+    Ok(OrderRequest {
+        name: normalize_name(&request.name)?,
+        order: request.order,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::orders::PizzaKindArray;
+
+    use super::*;
+
+    #[test]
+    fn names_are_trimmed_and_collapsed() {
+        assert_eq!(normalize_name("  Alice \t von\n Bob  "), Ok(String::from("Alice von Bob")));
+        assert_eq!(normalize_name(" \t\n"), Err(ValidationError::EmptyName));
+        assert_eq!(normalize_name("a\u{7}b"), Err(ValidationError::InvalidCharacter));
+    }
+
+    #[test]
+    fn name_length_is_counted_in_characters_after_normalization() {
+        let longest = "ä".repeat(MAX_NAME_LENGTH);
+        assert_eq!(normalize_name(&longest), Ok(longest.clone()));
+        assert_eq!(normalize_name(&format!("  {longest}  ")), Ok(longest.clone()));
+        assert_eq!(normalize_name(&format!("{longest}ä")), Err(ValidationError::NameTooLong));
+    }
+
+    #[test]
+    fn slices_are_capped_per_kind_and_in_total() {
+        assert_eq!(validate_amounts(&PizzaKindArray([MAX_AMOUNT_PER_KIND, 0, 0])), Ok(()));
+        assert_eq!(validate_amounts(&PizzaKindArray([0, MAX_AMOUNT_PER_KIND + 1, 0])), Err(ValidationError::TooManyOfKind(PizzaKind::Vegetarian)));
+        assert_eq!(validate_amounts(&PizzaKindArray([MAX_TOTAL_AMOUNT / 2, MAX_TOTAL_AMOUNT / 2, 0])), Ok(()));
+        assert_eq!(validate_amounts(&PizzaKindArray([MAX_TOTAL_AMOUNT / 2, MAX_TOTAL_AMOUNT / 2, 1])), Err(ValidationError::TooManyInTotal));
+        assert_eq!(validate_amounts(&PizzaKindArray([0, 0, 0])), Err(ValidationError::NoSlices));
+    }
+
+    #[test]
+    fn preference_has_to_be_finite_and_between_zero_and_one() {
+        assert_eq!(validate_preference(0.0), Ok(()));
+        assert_eq!(validate_preference(1.0), Ok(()));
+        for preference in [-0.1, 1.1, Preference::NAN, Preference::INFINITY, Preference::NEG_INFINITY] {
+            assert_eq!(validate_preference(preference), Err(ValidationError::InvalidPreference), "{preference}");
+        }
+    }
+
+    #[test]
+    fn kind_errors_use_the_kind_name() {
+        assert_eq!(ValidationError::TooManyOfKind(PizzaKind::Vegan).to_string(), format!("at most {MAX_AMOUNT_PER_KIND} slices of kind vegan can be ordered"));
+    }
+}
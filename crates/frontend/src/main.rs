@@ -1,21 +1,138 @@
-use yew::prelude::*;
+mod tokens;
+
+use gloo_net::http::{Request, Response};
+use pizza_bot_rs_common::{communication::{DeleteOrderResponse, MakeOrderResponse, ServerError}, orders::OrderRequest, syntax, validation};
+use web_sys::HtmlInputElement;
+use yew::{platform::spawn_local, prelude::*};
+
+/// Current text of the input the event came from
+fn input_value(event: InputEvent) -> String {
+    event.target_unchecked_into::<HtmlInputElement>().value()
+}
+
+/// Message for a response that does not carry the expected body, like a [`ServerError`] when rate limited
+/// or the plain text rejection of a malformed request
+async fn rejection(response: Response) -> String {
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+
+    match serde_json::from_str::<ServerError>(&text) {
+        Ok(err) => err.message,
+        Err(_) if !text.trim().is_empty() => text,
+        Err(_) => format!("The server answered with status {status}"),
+    }
+}
+
+/// Makes the order and keeps its edit token in this browser
+async fn make_order(request: OrderRequest) -> Result<String, String> {
+    let response = Request::post("/api/orders")
+        .json(&request)
+        .map_err(|err| err.to_string())?
+        .send()
+        .await
+        .map_err(|err| format!("Could not reach the server, {err}"))?;
+
+    // Created, Conflict and Unprocessable Entity carry a `MakeOrderResponse`
+    if !matches!(response.status(), 201 | 409 | 422) {
+        return Err(rejection(response).await)
+    }
+
+    match response.json::<MakeOrderResponse>().await.map_err(|err| err.to_string())? {
+        MakeOrderResponse::Success(token) => {
+            tokens::store(&request.name, token);
+            Ok(format!("Ordered as `{}`", request.name))
+        },
+        MakeOrderResponse::NameAlreadyRegistered => Err(format!("There already is an order of `{}`", request.name)),
+        MakeOrderResponse::TooManyOrders => Err(String::from("The round is full")),
+        MakeOrderResponse::Invalid(err) => Err(format!("Invalid order, {err}")),
+    }
+}
+
+/// Deletes the order with the edit token stored in this browser
+async fn delete_order(name: String) -> Result<String, String> {
+    let Some(token) = tokens::load(&name) else {
+        return Err(format!("The order of `{name}` was not made in this browser"))
+    };
+
+    let response = Request::delete(&format!("/api/orders/{}", js_sys::encode_uri_component(&name)))
+        .header("Authorization", &format!("Bearer {token}"))
+        .send()
+        .await
+        .map_err(|err| format!("Could not reach the server, {err}"))?;
+
+    // OK, Forbidden and Not Found carry a `DeleteOrderResponse`
+    if !matches!(response.status(), 200 | 403 | 404) {
+        return Err(rejection(response).await)
+    }
+
+    match response.json::<DeleteOrderResponse>().await.map_err(|err| err.to_string())? {
+        DeleteOrderResponse::Success => {
+            tokens::remove(&name);
+            Ok(format!("Deleted the order of `{name}`"))
+        },
+        DeleteOrderResponse::NameNotFound => {
+            tokens::remove(&name);
+            Err(format!("There is no order of `{name}`"))
+        },
+        DeleteOrderResponse::InvalidToken => Err(String::from("The stored edit token is no longer valid")),
+    }
+}
 
 #[function_component]
 fn App() -> Html {
-    let counter = use_state(|| 0);
-    let onclick = {
-        let counter = counter.clone();
+    let name = use_state(String::new);
+    let order = use_state(String::new);
+    let status = use_state(|| None::<Result<String, String>>);
+
+    let on_name = {
+        let name = name.clone();
+        move |event: InputEvent| name.set(input_value(event))
+    };
+    let on_order = {
+        let order = order.clone();
+        move |event: InputEvent| order.set(input_value(event))
+    };
+
+    let on_submit = {
+        let (name, order, status) = (name.clone(), order.clone(), status.clone());
+        move |event: SubmitEvent| {
+            event.prevent_default();
+
+            // Checked against the same limits as on the server, so invalid orders are never sent
+            let request = match syntax::parse_request(&name, &order) {
+                Ok(request) => request,
+                Err(err) => return status.set(Some(Err(err.to_string()))),
+            };
+
+            let status = status.clone();
+            spawn_local(async move { status.set(Some(make_order(request).await)) });
+        }
+    };
+    let on_delete = {
+        let (name, status) = (name.clone(), status.clone());
         move |_| {
-            let value = *counter + 1;
-            counter.set(value);
+            let Ok(name) = validation::normalize_name(&name) else {
+                return status.set(Some(Err(String::from("Enter the name of the order to delete"))))
+            };
+            let status = status.clone();
+            spawn_local(async move { status.set(Some(delete_order(name).await)) });
         }
     };
 
     html! {
-        <div>
-            <button {onclick}>{ "+1" }</button>
-            <p>{ *counter }</p>
-        </div>
+        <form onsubmit={on_submit}>
+            <input placeholder="Name" value={(*name).clone()} oninput={on_name} />
+            <input placeholder="3 meat, 2 veg; pref 0.4" value={(*order).clone()} oninput={on_order} />
+            <button type="submit">{ "Order" }</button>
+            <button type="button" onclick={on_delete}>{ "Delete" }</button>
+            {
+                match &*status {
+                    Some(Ok(message)) => html! { <p>{ message }</p> },
+                    Some(Err(message)) => html! { <p style="color: red">{ message }</p> },
+                    None => html! {},
+                }
+            }
+        </form>
     }
 }
 
@@ -0,0 +1,38 @@
+//! Edit tokens of the orders made from this browser, kept in local storage
+
+use std::collections::HashMap;
+
+use gloo_storage::{LocalStorage, Storage};
+use pizza_bot_rs_common::validation;
+
+const TOKENS_KEY: &str = "pizzabot.tokens";
+
+/// Stores tokens under the name the server knows the order by
+fn key(name: &str) -> String {
+    validation::normalize_name(name).unwrap_or_else(|_| name.to_owned())
+}
+
+fn load_all() -> HashMap<String, String> {
+    LocalStorage::get(TOKENS_KEY).unwrap_or_default()
+}
+
+/// Returns the stored edit token for the order of `name`
+pub fn load(name: &str) -> Option<String> {
+    load_all().remove(&key(name))
+}
+
+/// Remembers the edit token returned when creating the order of `name`
+pub fn store(name: &str, token: String) {
+    let mut tokens = load_all();
+    tokens.insert(key(name), token);
+    // Storage may be full or disabled, in which case the token only lives for this session
+    let _ = LocalStorage::set(TOKENS_KEY, tokens);
+}
+
+/// Forgets the edit token of a deleted order
+pub fn remove(name: &str) {
+    let mut tokens = load_all();
+    if tokens.remove(&key(name)).is_some() {
+        let _ = LocalStorage::set(TOKENS_KEY, tokens);
+    }
+}